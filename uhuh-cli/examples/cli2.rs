@@ -1,4 +1,4 @@
-use uhuh_cli::{BuilderExt, Cli, CliBuilder, CliSetupContext};
+use uhuh_cli::{BuilderExt, Cli, CliBuildContext, CliBuilder, CliSetupContext};
 use uhuh_exp::{
     extensions::{PluginsList, Setup, SetupBuildContext, SetupList},
     serde, BuildContext, Builder, Config, DynamicModule, Module, UhuhError,
@@ -44,6 +44,25 @@ pub struct Context {
 //     }
 // }
 
+impl uhuh_ext::Context for Context {
+    fn get<T: 'static + Send + Sync>(&self) -> Option<&T> {
+        self.ext.get()
+    }
+
+    fn register<T: 'static + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.ext.insert(value)
+    }
+}
+
+impl CliBuildContext for Context {
+    fn build_context(&mut self) -> BuildCtx<'_> {
+        BuildCtx {
+            extensions: &mut self.ext,
+            plugins: &mut self.plugins,
+        }
+    }
+}
+
 impl SetupBuildContext<Context> for Context {
     fn register_constant<T>(&mut self, setup: T) -> Result<(), UhuhError>
     where
@@ -126,6 +145,16 @@ impl BuildContext for Context {
                     })
                     .await?;
             }
+
+            let setup = core::mem::take(&mut self.setup);
+            setup
+                .build(SetupCtx {
+                    cmds: &mut self.cmds,
+                    setup: &mut SetupList::default(),
+                    ext: &mut self.ext,
+                })
+                .await?;
+
             Ok(())
         }
     }
@@ -165,7 +194,7 @@ impl BuildContext for Context {
 
 struct TestModule;
 
-impl<C: BuildContext> Module<C> for TestModule {
+impl Module<Context> for TestModule {
     const CONFIG_SECTION: &'static str = "test";
 
     type Config = Value;
@@ -173,16 +202,17 @@ impl<C: BuildContext> Module<C> for TestModule {
     type Error = UhuhError;
 
     fn setup(
-        _ctx: <C as BuildContext>::Setup<'_>,
+        mut ctx: <Context as BuildContext>::Setup<'_>,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> {
         async move {
             println!("Setup");
+            ctx.register_command(Hello)?;
             Ok(())
         }
     }
 
     fn build(
-        mut ctx: <C as BuildContext>::Build<'_>,
+        mut ctx: <Context as BuildContext>::Build<'_>,
         config: Option<Self::Config>,
     ) -> impl std::future::Future<Output = Result<(), Self::Error>> {
         async move {
@@ -203,7 +233,7 @@ impl Cli<Context> for App {
     fn run(
         self,
         ctx: Extensions,
-        args: &clap::ArgAction,
+        args: clap::ArgMatches,
     ) -> impl futures::Future<Output = Result<(), UhuhError>> {
         async move {
             println!("Worm");
@@ -212,6 +242,41 @@ impl Cli<Context> for App {
     }
 }
 
+pub struct Greeting(String);
+
+pub struct Hello;
+
+impl Cli<Context> for Hello {
+    fn create_command(&self) -> clap::Command {
+        clap::Command::new("hello").arg(clap::Arg::new("name").default_value("world"))
+    }
+
+    fn prepare<'a, 'b>(
+        &'a self,
+        builder: &'a mut <Context as BuildContext>::Build<'b>,
+        args: &'a clap::ArgMatches,
+    ) -> impl futures::Future<Output = Result<(), UhuhError>> + 'a {
+        async move {
+            let name = args.get_one::<String>("name").cloned().unwrap_or_default();
+            builder.extensions.insert(Greeting(name));
+            Ok(())
+        }
+    }
+
+    fn run(
+        self,
+        ctx: Extensions,
+        _args: clap::ArgMatches,
+    ) -> impl futures::Future<Output = Result<(), UhuhError>> {
+        async move {
+            if let Some(Greeting(name)) = ctx.get::<Greeting>() {
+                println!("Hello, {name}!");
+            }
+            Ok(())
+        }
+    }
+}
+
 fn main() {
     futures::executor::block_on(wrapped_main()).unwrap()
 }
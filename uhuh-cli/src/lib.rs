@@ -1,6 +1,6 @@
 // mod cmd;
 
-use std::{future::Future, process::Output};
+use std::{ffi::OsString, future::Future, process::Output};
 
 use uhuh_config::{ConfigResolver, FsConfigResolver};
 use uhuh_exp::{
@@ -8,12 +8,14 @@ use uhuh_exp::{
     BuildContext, Builder, LocalBoxFuture, SetupPhase, UhuhError,
 };
 
-// pub trait CliBuildContext: BuildContext + SetupBuildContext<Self> + Sized
-// // where
-// //     for<'a> Self::Setup<'a>: CliSetupContext<Self>,
-// {
-//     fn register_command<T: Cli<Self> + 'static>(&mut self, cli: T);
-// }
+/// A context able to create a build context outside of the modules,
+/// so the selected command can prepare it before the modules are built.
+pub trait CliBuildContext: BuildContext {
+    /// The returned build context is dropped before the modules are built,
+    /// so it must write through to this context's own storage. Anything the
+    /// selected command's `prepare` registers has to be visible to the modules.
+    fn build_context(&mut self) -> Self::Build<'_>;
+}
 
 pub trait CliSetupContext<C: BuildContext>: ConfigureSetup<C> {
     fn register_command<T: Cli<C> + Sync + Send + 'static>(
@@ -38,38 +40,81 @@ where
 
 pub trait BuilderExt<C>
 where
-    C: BuildContext,
+    C: BuildContext + CliBuildContext,
     for<'a> C::Setup<'a>: CliSetupContext<C>,
 {
     fn cli<T>(self, cli: T) -> impl Future<Output = Result<(), UhuhError>>
     where
         T: Cli<C>;
+
+    /// Like [`BuilderExt::cli`], but parses `args` instead of the process arguments.
+    fn cli_from<T, I, A>(self, cli: T, args: I) -> impl Future<Output = Result<(), UhuhError>>
+    where
+        T: Cli<C>,
+        I: IntoIterator<Item = A>,
+        A: Into<OsString> + Clone;
 }
 
 impl<C: 'static> BuilderExt<C> for Builder<SetupPhase<C>, C>
 where
-    C: BuildContext + SetupBuildContext<C> + uhuh_ext::Context,
+    C: BuildContext + CliBuildContext + SetupBuildContext<C> + uhuh_ext::Context,
     for<'a> C::Setup<'a>: CliSetupContext<C>,
 {
     fn cli<T>(self, cli: T) -> impl Future<Output = Result<(), UhuhError>>
     where
         T: Cli<C>,
+    {
+        self.cli_from(cli, std::env::args_os())
+    }
+
+    fn cli_from<T, I, A>(self, cli: T, args: I) -> impl Future<Output = Result<(), UhuhError>>
+    where
+        T: Cli<C>,
+        I: IntoIterator<Item = A>,
+        A: Into<OsString> + Clone,
     {
         async move {
             let mut builder = self.constant(CliBuilder::default())?.setup().await?;
 
-            let subcommands = builder
+            // Swap in an empty list to take ownership of the registered commands
+            let SubCommands(mut cmds) = builder
                 .context()
-                .get::<CliBuilder<C>>()
+                .register(SubCommands::<C>(Vec::new()))
                 .ok_or_else(|| UhuhError::new("Cli builder not registered"))?;
 
-            let app = cli.create_command();
+            let mut app = cli.create_command();
+            let mut names = Vec::with_capacity(cmds.len());
+
+            for cmd in &cmds {
+                let cmd = cmd.create_command();
+                names.push(cmd.get_name().to_string());
+                app = app.subcommand(cmd);
+            }
 
-            let args = app.get_matches();
+            let matches = app.get_matches_from(args);
 
-            // cli.prepare(builder);
+            let selected = matches.subcommand().and_then(|(name, args)| {
+                names
+                    .iter()
+                    .position(|n| n == name)
+                    .map(|idx| (idx, args.clone()))
+            });
 
-            Ok(())
+            match selected {
+                Some((idx, args)) => {
+                    let cmd = cmds.swap_remove(idx);
+                    cmd.prepare(&mut builder.context().build_context(), &args)
+                        .await?;
+                    let output = builder.build().await?.init().await?;
+                    cmd.run(output, args).await
+                }
+                None => {
+                    cli.prepare(&mut builder.context().build_context(), &matches)
+                        .await?;
+                    let output = builder.build().await?.init().await?;
+                    cli.run(output, matches).await
+                }
+            }
         }
     }
 }
@@ -77,16 +122,21 @@ where
 #[allow(unused)]
 pub trait Cli<C: BuildContext> {
     fn create_command(&self) -> clap::Command;
+
+    /// Called before the modules are built, only if this command is selected.
+    /// `args` are the matches of this command.
     fn prepare<'a, 'b>(
         &'a self,
         builder: &'a mut C::Build<'b>,
+        args: &'a clap::ArgMatches,
     ) -> impl Future<Output = Result<(), UhuhError>> + 'a {
         async move { Ok(()) }
     }
+
     fn run(
         self,
         ctx: C::Output,
-        args: &clap::ArgAction,
+        args: clap::ArgMatches,
     ) -> impl Future<Output = Result<(), UhuhError>>;
 }
 
@@ -95,13 +145,17 @@ trait DynCli<C: BuildContext> {
     fn prepare<'a, 'b>(
         &'a self,
         builder: &'a mut C::Build<'b>,
+        args: &'a clap::ArgMatches,
     ) -> LocalBoxFuture<'a, Result<(), UhuhError>>;
 
     fn run<'a>(
-        self,
+        self: Box<Self>,
         ctx: C::Output,
-        args: &'a clap::ArgAction,
-    ) -> LocalBoxFuture<'a, Result<(), UhuhError>>;
+        args: clap::ArgMatches,
+    ) -> LocalBoxFuture<'a, Result<(), UhuhError>>
+    where
+        Self: 'a,
+        C::Output: 'a;
 }
 
 struct CliBox<T>(T);
@@ -118,15 +172,20 @@ where
     fn prepare<'a, 'b>(
         &'a self,
         builder: &'a mut C::Build<'b>,
+        args: &'a clap::ArgMatches,
     ) -> LocalBoxFuture<'a, Result<(), UhuhError>> {
-        Box::pin(async move { self.0.prepare(builder).await })
+        Box::pin(async move { self.0.prepare(builder, args).await })
     }
 
     fn run<'a>(
-        self,
+        self: Box<Self>,
         ctx: <C as BuildContext>::Output,
-        args: &'a clap::ArgAction,
-    ) -> LocalBoxFuture<'a, Result<(), UhuhError>> {
+        args: clap::ArgMatches,
+    ) -> LocalBoxFuture<'a, Result<(), UhuhError>>
+    where
+        Self: 'a,
+        C::Output: 'a,
+    {
         Box::pin(async move { self.0.run(ctx, args).await })
     }
 }
@@ -166,3 +225,223 @@ impl<C: BuildContext> Setup<C> for CliBuilder<C> {
 }
 
 pub struct SubCommands<C>(Vec<Box<dyn DynCli<C> + Sync + Send>>);
+
+#[cfg(test)]
+mod tests {
+    use uhuh_exp::{extensions::SetupList, DynamicModule, Module};
+    use uhuh_ext::Extensions;
+
+    use super::*;
+
+    struct Context {
+        ext: Extensions,
+        setup: SetupList<Self>,
+    }
+
+    impl uhuh_ext::Context for Context {
+        fn get<T: 'static + Send + Sync>(&self) -> Option<&T> {
+            self.ext.get()
+        }
+
+        fn register<T: 'static + Send + Sync>(&mut self, value: T) -> Option<T> {
+            self.ext.insert(value)
+        }
+    }
+
+    impl SetupBuildContext<Context> for Context {
+        fn register_constant<T>(&mut self, setup: T) -> Result<(), UhuhError>
+        where
+            T: 'static + Setup<Context>,
+            T::Output: Send + Sync + 'static,
+            T::Error: 'static,
+        {
+            self.setup.insert(setup)
+        }
+    }
+
+    impl CliBuildContext for Context {
+        fn build_context(&mut self) -> BuildCtx<'_> {
+            BuildCtx { ext: &mut self.ext }
+        }
+    }
+
+    struct SetupCtx<'a> {
+        ext: &'a mut Extensions,
+        setup: &'a mut SetupList<Context>,
+    }
+
+    impl<'a> uhuh_ext::Context for SetupCtx<'a> {
+        fn get<T: 'static + Send + Sync>(&self) -> Option<&T> {
+            self.ext.get()
+        }
+
+        fn register<T: 'static + Send + Sync>(&mut self, value: T) -> Option<T> {
+            self.ext.insert(value)
+        }
+    }
+
+    impl<'a> ConfigureSetup<Context> for SetupCtx<'a> {
+        fn configure_setup<T>(&mut self) -> Result<&mut T, UhuhError>
+        where
+            T: 'static + Setup<Context>,
+            T::Output: Send + Sync + 'static,
+            T::Error: 'static,
+        {
+            self.setup.get_mut()
+        }
+    }
+
+    struct BuildCtx<'a> {
+        ext: &'a mut Extensions,
+    }
+
+    impl BuildContext for Context {
+        type Setup<'a> = SetupCtx<'a>;
+        type Build<'a> = BuildCtx<'a>;
+        type Init<'a> = ();
+        type Config = ();
+        type Output = Extensions;
+
+        fn run_setup<'a>(
+            &'a mut self,
+            modules: &'a [Box<dyn DynamicModule<Self>>],
+        ) -> impl Future<Output = Result<(), UhuhError>> + 'a {
+            async move {
+                for module in modules {
+                    module
+                        .setup(SetupCtx {
+                            ext: &mut self.ext,
+                            setup: &mut self.setup,
+                        })
+                        .await?;
+                }
+
+                let setup = core::mem::take(&mut self.setup);
+                setup
+                    .build(SetupCtx {
+                        ext: &mut self.ext,
+                        setup: &mut SetupList::default(),
+                    })
+                    .await
+            }
+        }
+
+        fn run_build<'a>(
+            &'a mut self,
+            modules: &'a [Box<dyn DynamicModule<Self>>],
+        ) -> impl Future<Output = Result<(), UhuhError>> + 'a {
+            async move {
+                for module in modules {
+                    module.build(BuildCtx { ext: &mut self.ext }, &()).await?;
+                }
+                Ok(())
+            }
+        }
+
+        fn run_init<'a>(
+            &'a mut self,
+            _modules: &'a [Box<dyn DynamicModule<Self>>],
+        ) -> impl Future<Output = Result<(), UhuhError>> + 'a {
+            async move { Ok(()) }
+        }
+
+        fn build(self) -> impl Future<Output = Result<Self::Output, UhuhError>> {
+            async move { Ok(self.ext) }
+        }
+    }
+
+    struct Greeting(String);
+
+    struct Built(String);
+
+    struct TestModule;
+
+    impl Module<Context> for TestModule {
+        const CONFIG_SECTION: &'static str = "test";
+
+        type Config = ();
+        type Error = UhuhError;
+
+        fn setup(mut ctx: SetupCtx<'_>) -> impl Future<Output = Result<(), Self::Error>> {
+            async move { ctx.register_command(Hello) }
+        }
+
+        fn build(
+            mut ctx: BuildCtx<'_>,
+            _config: Option<Self::Config>,
+        ) -> impl Future<Output = Result<(), Self::Error>> {
+            async move {
+                let Some(Greeting(name)) = ctx.ext.get::<Greeting>() else {
+                    return Err(UhuhError::new("prepare not visible during build"));
+                };
+                let name = name.clone();
+                ctx.ext.insert(Built(name));
+                Ok(())
+            }
+        }
+    }
+
+    struct App;
+
+    impl Cli<Context> for App {
+        fn create_command(&self) -> clap::Command {
+            clap::Command::new("app")
+        }
+
+        fn run(
+            self,
+            _ctx: Extensions,
+            _args: clap::ArgMatches,
+        ) -> impl Future<Output = Result<(), UhuhError>> {
+            async move { Err(UhuhError::new("subcommand not selected")) }
+        }
+    }
+
+    struct Hello;
+
+    impl Cli<Context> for Hello {
+        fn create_command(&self) -> clap::Command {
+            clap::Command::new("hello").arg(clap::Arg::new("name").default_value("world"))
+        }
+
+        fn prepare<'a, 'b>(
+            &'a self,
+            builder: &'a mut BuildCtx<'b>,
+            args: &'a clap::ArgMatches,
+        ) -> impl Future<Output = Result<(), UhuhError>> + 'a {
+            async move {
+                let name = args.get_one::<String>("name").cloned().unwrap_or_default();
+                builder.ext.insert(Greeting(name));
+                Ok(())
+            }
+        }
+
+        fn run(
+            self,
+            ctx: Extensions,
+            _args: clap::ArgMatches,
+        ) -> impl Future<Output = Result<(), UhuhError>> {
+            async move {
+                match ctx.get::<Built>() {
+                    Some(Built(name)) if name == "uhuh" => Ok(()),
+                    _ => Err(UhuhError::new("module was not built from prepare")),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prepare_is_visible_during_build() {
+        let context = Context {
+            ext: Extensions::new(),
+            setup: SetupList::default(),
+        };
+
+        futures::executor::block_on(
+            Builder::new(context)
+                .module::<TestModule>()
+                .cli_from(App, ["app", "hello", "uhuh"]),
+        )
+        .unwrap();
+    }
+}
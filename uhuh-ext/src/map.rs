@@ -1,7 +1,10 @@
 use core::any::{Any, TypeId};
 use core::fmt;
 
-use alloc::boxed::Box;
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+};
 
 type FxHashMap<K, V> =
     hashbrown::HashMap<K, V, core::hash::BuildHasherDefault<rustc_hash::FxHasher>>;
@@ -47,6 +50,11 @@ impl Extensions {
             .and_then(|boxed| (&**boxed as &(dyn Any + 'static)).downcast_ref())
     }
 
+    /// Get a reference to a typed value, or an error naming the missing type.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&T, MissingExtension> {
+        self.get::<T>().ok_or_else(MissingExtension::new::<T>)
+    }
+
     /// Get a mutable reference to a typed value previously inserted on this `Extensions`.
     pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
         self.map
@@ -78,3 +86,49 @@ impl fmt::Debug for Extensions {
         f.debug_struct("Extensions").finish()
     }
 }
+
+/// Returned from [`Extensions::require`] when no value of the type is registered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingExtension {
+    type_name: &'static str,
+    provider: Option<String>,
+}
+
+impl MissingExtension {
+    pub fn new<T: 'static>() -> MissingExtension {
+        MissingExtension {
+            type_name: core::any::type_name::<T>(),
+            provider: None,
+        }
+    }
+
+    /// Name the module or plugin that was expected to register the type
+    pub fn with_provider(mut self, provider: Option<&str>) -> MissingExtension {
+        self.provider = provider.map(|m| m.to_string());
+        self
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// The module or plugin that registers the type, if known
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+}
+
+impl fmt::Display for MissingExtension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.provider {
+            Some(provider) => write!(
+                f,
+                "'{}' is not registered. It is expected from '{}'",
+                self.type_name, provider
+            ),
+            None => write!(f, "'{}' is not registered", self.type_name),
+        }
+    }
+}
+
+impl core::error::Error for MissingExtension {}
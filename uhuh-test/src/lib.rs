@@ -0,0 +1,285 @@
+//! Test support for uhuh applications.
+//!
+//! [`TestApp`] builds a whole app from in-memory config in a temporary root directory.
+//! [`ModuleHarness`] runs the phases of a single module without the rest of the app.
+//!
+//! ```ignore
+//! let run = TestApp::new(())?
+//!     .config("db", json!({ "url": "sqlite::memory:" }))?
+//!     .module::<Db>()
+//!     .mock(FakeMailer::default())
+//!     .build()
+//!     .await?;
+//!
+//! run.assert_ran("db", &[ModulePhase::Build, ModulePhase::Init]);
+//! ```
+
+use std::path::Path;
+
+use bobestyrer::Tokio;
+use tempfile::TempDir;
+use uhuh::{
+    builder::{set_section, Builder, Setup, Shutdown},
+    Config, Context, Error, Mode, Module, ModulePhase, Outcome, StartupReport,
+};
+
+pub use uhuh::builder::ModuleHarness;
+
+/// Builds an app from in-memory config, recording the phases each module runs
+pub struct TestApp<C: Context> {
+    builder: Builder<Setup<C>>,
+    report: StartupReport,
+    root: TempDir,
+}
+
+impl<C: Context + 'static> TestApp<C> {
+    /// A builder in development mode with an empty temporary root and no config files.
+    /// Must be called from within a tokio runtime.
+    pub fn new(ctx: C) -> Result<TestApp<C>, Error> {
+        let root = TempDir::new().map_err(Error::new)?;
+
+        let builder = Builder::new(ctx, "test", Mode::Development, Tokio::from_global())
+            .root(root.path())
+            .startup_report(true);
+
+        let report = builder.report().unwrap_or_default();
+
+        Ok(TestApp {
+            builder,
+            report,
+            root,
+        })
+    }
+
+    /// The temporary root directory. It is removed when the [`TestRun`] is dropped.
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// The underlying builder, for anything not covered here
+    pub fn builder(&mut self) -> &mut Builder<Setup<C>> {
+        &mut self.builder
+    }
+
+    pub fn module<T: Module<C> + 'static>(mut self) -> Self {
+        self.builder.add_module::<T>();
+        self
+    }
+
+    /// Add a named instance of `T` reading the config section `name`, like `db.replica`
+    pub fn module_named<T: Module<C> + 'static>(mut self, name: &str) -> Result<Self, Error> {
+        self.builder.add_module_named::<T>(name)?;
+        Ok(self)
+    }
+
+    /// Set the config section `section`, like `db` or `db.replica`
+    pub fn config<T: serde::Serialize>(mut self, section: &str, value: T) -> Result<Self, Error> {
+        let section = section.to_string();
+        let value = vaerdi::ser::to_value(value)?;
+
+        self.builder
+            .add_configure(move |cfg: &mut Config| set_section(cfg, &section, value));
+
+        Ok(self)
+    }
+
+    /// Merge a whole config, as if it was read from a config file
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.builder.add_configure(config);
+        self
+    }
+
+    /// Replace the value of type `T`. Modules registering `T` are ignored,
+    /// so they and their dependents see the mock from build on.
+    pub fn mock<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.builder.add_override_ext(value);
+        self
+    }
+
+    pub async fn build(self) -> Result<TestRun<C>, Error> {
+        let (output, shutdown) = self.builder.build().await?;
+
+        Ok(TestRun {
+            output,
+            shutdown,
+            report: self.report,
+            root: self.root,
+        })
+    }
+}
+
+/// A built app, with the phases each module ran
+pub struct TestRun<C: Context> {
+    output: C::Output,
+    shutdown: Shutdown<C>,
+    report: StartupReport,
+    root: TempDir,
+}
+
+impl<C: Context> TestRun<C> {
+    pub fn output(&self) -> &C::Output {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut C::Output {
+        &mut self.output
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    pub fn report(&self) -> &StartupReport {
+        &self.report
+    }
+
+    /// The phases `module` completed, in order. `module` is the config section of the module.
+    pub fn phases(&self, module: &str) -> Vec<ModulePhase> {
+        self.report
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.module == module && entry.outcome == Outcome::Completed)
+            .map(|entry| entry.phase)
+            .collect()
+    }
+
+    pub fn ran(&self, module: &str, phase: ModulePhase) -> bool {
+        self.phases(module).contains(&phase)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `module` did not complete all of `phases`.
+    #[track_caller]
+    pub fn assert_ran(&self, module: &str, phases: &[ModulePhase]) {
+        let ran = self.phases(module);
+        let missing = phases
+            .iter()
+            .filter(|phase| !ran.contains(phase))
+            .collect::<Vec<_>>();
+
+        assert!(
+            missing.is_empty(),
+            "module '{module}' did not run {missing:?}. It ran {ran:?}\n{}",
+            self.report
+        );
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `module` completed `phase`.
+    #[track_caller]
+    pub fn assert_not_ran(&self, module: &str, phase: ModulePhase) {
+        assert!(
+            !self.ran(module, phase),
+            "module '{module}' ran {phase}\n{}",
+            self.report
+        );
+    }
+
+    /// Run the module shutdown hooks and return the output.
+    /// The temporary root is removed.
+    pub async fn shutdown(self) -> Result<C::Output, Error> {
+        self.shutdown.run().await?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uhuh::{BuildCtx, InitCtx};
+
+    #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct DbConfig {
+        url: String,
+    }
+
+    struct Db;
+
+    impl Module<()> for Db {
+        const CONFIG_SECTION: &'static str = "db";
+        type Config = DbConfig;
+
+        fn default_config() -> Option<DbConfig> {
+            None
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, config: DbConfig) -> Result<(), Error> {
+            ctx.register(config);
+            Ok(())
+        }
+
+        async fn init(mut ctx: InitCtx<'_, ()>) -> Result<(), Error> {
+            let url = ctx.require::<DbConfig>()?.url.clone();
+            ctx.register(url);
+            Ok(())
+        }
+    }
+
+    fn db(url: &str) -> DbConfig {
+        DbConfig {
+            url: url.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn builds_a_module_from_in_memory_config() {
+        let run = TestApp::new(())
+            .unwrap()
+            .config("db", db("sqlite::memory:"))
+            .unwrap()
+            .module::<Db>()
+            .build()
+            .await
+            .unwrap();
+
+        run.assert_ran("db", &[ModulePhase::Build, ModulePhase::Init]);
+        assert_eq!(run.output().get::<DbConfig>(), Some(&db("sqlite::memory:")));
+    }
+
+    #[tokio::test]
+    async fn config_keeps_sibling_sections() {
+        let run = TestApp::new(())
+            .unwrap()
+            .config("db.primary", db("primary"))
+            .unwrap()
+            .config("db.replica", db("replica"))
+            .unwrap()
+            .module_named::<Db>("db.primary")
+            .unwrap()
+            .module_named::<Db>("db.replica")
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        let app = run.output();
+        assert_eq!(
+            app.get_named::<DbConfig>("db.primary"),
+            Some(&db("primary"))
+        );
+        assert_eq!(
+            app.get_named::<DbConfig>("db.replica"),
+            Some(&db("replica"))
+        );
+    }
+
+    #[tokio::test]
+    async fn harness_runs_a_single_module() {
+        let mut harness = ModuleHarness::new((), std::env::temp_dir());
+        harness.set_config("db", db("harness")).unwrap();
+
+        harness.run::<Db>().await.unwrap();
+
+        assert_eq!(harness.get::<DbConfig>(), Some(&db("harness")));
+        assert_eq!(harness.get::<String>().map(|m| m.as_str()), Some("harness"));
+    }
+
+    #[tokio::test]
+    async fn harness_fails_without_config() {
+        let mut harness = ModuleHarness::new((), std::env::temp_dir());
+
+        assert!(harness.build::<Db>().await.is_err());
+    }
+}
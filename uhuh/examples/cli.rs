@@ -17,10 +17,11 @@ impl<C: Context + 'static> Module<C> for Test {
     }
 
     fn setup(mut core: uhuh::builder::SetupCtx<'_, C>) -> Result<(), Error> {
-        core.cmd(clap::Command::new("test"), |_app, _args| async move {
-            println!("Test");
+        // Runs as `cli test hello`
+        core.cmd(clap::Command::new("hello"), |_app, _args| async move {
+            println!("Hello from test");
             Ok(())
-        });
+        })?;
         Ok(())
     }
 
@@ -59,7 +60,7 @@ async fn main() -> Result<(), Error> {
             Ok(())
         })
         .cli(|app: Uhuh, _args| async move {
-            println!("App: {:?}", app.config());
+            println!("App: {:?}", *app.config());
             Ok(())
         })
         .await
@@ -1,7 +1,8 @@
 use bobestyrer::Tokio;
 use uhuh::{
+    async_initializer,
     builder::{register_ext, InitCtx},
-    Builder, Config, Context, Error, Mode, Module,
+    Builder, Config, Context, Error, InitializerExt, Mode, Module,
 };
 use vaerdi::Value;
 
@@ -17,8 +18,7 @@ impl<C: Context + 'static> Module<C> for Test {
     }
 
     fn setup(mut core: uhuh::builder::SetupCtx<'_, C>) -> Result<(), Error> {
-        core.add_module::<Test2>();
-        core.add_module::<Test2>();
+        core.depends_on::<Test2>()?;
         Ok(())
     }
 
@@ -62,7 +62,7 @@ impl<C: Context + 'static> Module<C> for Test2 {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Error> {
-    let app = Builder::new((), "Test", Mode::Development, Tokio::from_global())
+    let (_app, shutdown) = Builder::new((), "Test", Mode::Development, Tokio::from_global())
         .module::<Test>()
         .configure(|cfg: &mut Config| {
             cfg.try_set("rapper", 2022)?;
@@ -79,6 +79,14 @@ async fn main() -> Result<(), Error> {
 
             Ok(())
         })
+        .initializer(
+            async_initializer(async |core: InitCtx<'_, ()>| {
+                println!("before test: {:?}", core.get::<String>());
+                Ok(())
+            })
+            .named("greeting")
+            .before("test"),
+        )
         .configure(|cfg: &mut Config| {
             cfg.try_set("ostelone", "Freja")?;
 
@@ -87,5 +95,7 @@ async fn main() -> Result<(), Error> {
         .build()
         .await?;
 
+    shutdown.run().await?;
+
     Ok(())
 }
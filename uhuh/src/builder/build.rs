@@ -1,17 +1,35 @@
-use bobestyrer::AnyExecutor;
+use bobestyrer::{AnyExecutor, Executor, JoinHandle};
 use extensions::concurrent::Extensions;
 use futures_core::Future;
-use std::path::{Path, PathBuf};
-use tracing::debug;
+use futures_util::future::join_all;
+use johnfig::Config;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+use vaerdi::Value;
 
 use crate::{
-    context::Context, module::DynamicModule, plugin::PluginsList, Error, Initializer, Mode, Plugin,
+    context::Context,
+    module::DynamicModule,
+    named::{Lifecycle, NamedExtensions},
+    overrides::Overrides,
+    plugin::PluginsList,
+    providers::{MissingExtension, Providers},
+    report::{self, ModuleInfo, ModulePhase, ModuleStates, Outcome, StartupReport},
+    root::RootResolver,
+    ConfigError, Error, Initializer, Mode, Plugin,
 };
 
-use super::{config::ConfigBuilder, Builder, Init, Phase};
+use super::{
+    config::{get_section, ConfigBuilder, ConfigSources},
+    shutdown::abort,
+    Builder, Init, Phase, Shutdown,
+};
 
 #[cfg(feature = "cli")]
-use super::cmd::*;
+use super::{builtin, cmd::*, CliFlags};
 
 impl<C: Context> Builder<Build<C>> {
     pub async fn build(self) -> Result<Builder<Init<C>>, Error> {
@@ -20,7 +38,12 @@ impl<C: Context> Builder<Build<C>> {
         })
     }
 
-    pub async fn build_app(self) -> Result<C::Output, Error> {
+    /// All registered modules, including the ones added by other modules
+    pub fn modules(&self) -> Vec<ModuleInfo> {
+        self.phase.states.snapshot()
+    }
+
+    pub async fn build_app(self) -> Result<(C::Output, Shutdown<C>), Error> {
         self.phase.next().await?.next().await
     }
 
@@ -29,7 +52,8 @@ impl<C: Context> Builder<Build<C>> {
     where
         T: CmdAction<C>,
     {
-        self.cli_with(clap::Command::new("wilbur"), run).await
+        let app = clap::Command::new(self.phase.name.clone());
+        self.cli_with(app, run).await
     }
 
     #[cfg(feature = "cli")]
@@ -37,36 +61,84 @@ impl<C: Context> Builder<Build<C>> {
     where
         T: CmdAction<C>,
     {
-        let cmds = std::mem::take(&mut self.phase.cmds);
-
-        app = app
-            .arg(clap::Arg::new("config").long("config").short('c'))
-            .arg(
-                clap::Arg::new("mode")
-                    .long("mode")
-                    .default_value("development"),
-            )
-            .arg(clap::Arg::new("root").long("root").short('r'))
-            .name(self.phase.name.clone());
+        let mut cmds = std::mem::take(&mut self.phase.cmds);
+
+        app = self
+            .phase
+            .cli_flags
+            .apply(app.name(self.phase.name.clone()));
 
         for cmd in &cmds {
-            app = app.subcommand(&cmd.cmd);
+            let parents = &cmd.path[..cmd.path.len() - 1];
+            app = insert_cmd(app, parents, cmd.cmd.clone());
+        }
+
+        // A module may claim the names for its own commands
+        let unclaimed = |name: &str| !cmds.iter().any(|cmd| cmd.path[0] == name);
+        let builtin_config = unclaimed(builtin::CONFIG_COMMAND);
+        let builtin_completions = unclaimed(builtin::COMPLETIONS_COMMAND);
+        let builtin_manpage = unclaimed(builtin::MANPAGE_COMMAND);
+
+        if builtin_config {
+            app = app.subcommand(builtin::config_command());
+        }
+
+        if builtin_completions {
+            app = app.subcommand(builtin::completions_command());
         }
 
-        let cli = app.get_matches();
+        if builtin_manpage {
+            app = app.subcommand(builtin::manpage_command());
+        }
 
-        let mode = cli.get_one::<String>("mode");
+        self.phase.cli_flags.check(&app)?;
 
-        self.phase.mode = match mode.map(|m: &String| m.as_str()) {
-            Some("prod" | "production") => Mode::Production,
-            Some("dev" | "development") => Mode::Development,
-            _ => {
-                eprintln!("Unknown mode. using development");
-                Mode::Development
+        // Keep the assembled command around for completions and man pages
+        let cli = match app.try_get_matches_from_mut(std::env::args_os()) {
+            Ok(cli) => cli,
+            Err(err) if !err.use_stderr() => {
+                // --help and --version
+                err.print().map_err(Error::new)?;
+                return Ok(());
             }
+            Err(err) => return Err(Error::Usage(err.render().to_string())),
         };
 
-        if let Some(config_path) = cli.get_one::<String>("config") {
+        match cli.subcommand() {
+            Some((builtin::COMPLETIONS_COMMAND, args)) if builtin_completions => {
+                return builtin::run_completions(&mut app, args);
+            }
+            Some((builtin::MANPAGE_COMMAND, args)) if builtin_manpage => {
+                return builtin::run_manpage(app, args);
+            }
+            _ => {}
+        }
+
+        if let Some(level) = self.phase.cli_flags.apply_level(&cli) {
+            debug!(level = %level, "Log level set");
+            self.phase.extensions.insert(level);
+        }
+
+        if let Some(name) = flag_value(&cli, "mode") {
+            self.phase.mode = match name.parse::<Mode>() {
+                Ok(mode) if self.phase.modes.contains(&mode) => mode,
+                _ => {
+                    let modes = self
+                        .phase
+                        .modes
+                        .iter()
+                        .map(|m| m.as_str())
+                        .collect::<Vec<_>>();
+                    return Err(Error::Usage(format!(
+                        "unknown mode '{}'. Available modes: {}",
+                        name,
+                        modes.join(", ")
+                    )));
+                }
+            };
+        }
+
+        if let Some(config_path) = flag_value(&cli, "config") {
             debug!(config_path = ?config_path, "Using config paths");
 
             let path = PathBuf::from(config_path);
@@ -81,28 +153,46 @@ impl<C: Context> Builder<Build<C>> {
             }
         }
 
-        if let Some(root) = cli.get_one::<String>("root") {
+        if let Some(root) = flag_value(&cli, "root") {
             self.phase.root = Some(PathBuf::from(root).canonicalize().map_err(Error::new)?);
         }
 
         debug!(mode = ?self.phase.mode, "Mode set");
 
-        let app = self.phase.next().await?.next().await?;
-
-        match cli.subcommand() {
-            Some((name, args)) => {
-                let Some(cmd) = cmds.into_iter().find(|m| m.cmd.get_name() == name) else {
-                    eprintln!("unknown command");
-                    return Ok(());
-                };
+        if let Some((builtin::CONFIG_COMMAND, args)) = cli.subcommand() {
+            if builtin_config {
+                return builtin::run_config_command(self.phase, args).await;
+            }
+        }
 
-                cmd.action.call(app, args.clone()).await?;
+        let selected =
+            find_cmd(&cmds, &cli).map(|(idx, args)| (cmds.swap_remove(idx).action, args));
 
-                Ok(())
+        // Commands that need less than the full app stop the pipeline early
+        let (action, args) = match selected {
+            Some((CmdRun::Setup(action), args)) => {
+                debug!("Running command after setup");
+                let app = self.phase.into_app().await?;
+                return action.call(app, args).await;
+            }
+            Some((CmdRun::Build(action), args)) => {
+                debug!("Running command after build");
+                let (app, shutdown) = self.phase.next().await?.into_app();
+                return shutdown.run_until_signal(action.call(app, args)).await;
             }
+            Some((CmdRun::Init(action), args)) => (Some(action), args),
+            None if cli.subcommand().is_none() => (None, cli),
+            None => return Err(Error::Usage("unknown command".to_string())),
+        };
+
+        let (app, shutdown) = self.phase.next().await?.next().await?;
+
+        match action {
+            Some(action) => shutdown.run_until_signal(action.call(app, args)).await,
             None => {
-                Box::new(run).call(app, cli).await?;
-                Ok(())
+                shutdown
+                    .run_until_signal(Box::new(run).call(app, args))
+                    .await
             }
         }
     }
@@ -111,115 +201,598 @@ impl<C: Context> Builder<Build<C>> {
 pub struct Build<C> {
     pub(super) ctx: C,
     pub(super) modules: Vec<Box<dyn DynamicModule<C>>>,
+    pub(super) depths: Vec<usize>,
+    /// The positions of each module's dependencies in `modules`
+    pub(super) deps: Vec<Vec<usize>>,
+    pub(super) states: ModuleStates,
     pub(super) initializers: Vec<Box<dyn Initializer<C>>>,
     #[cfg(feature = "cli")]
     pub(super) cmds: Vec<Cmd<C>>,
+    #[cfg(feature = "cli")]
+    pub(super) cli_flags: CliFlags,
     pub(super) extensions: Extensions,
+    pub(super) providers: Providers,
+    pub(super) overrides: Overrides,
+    pub(super) named: NamedExtensions,
     pub(super) config: ConfigBuilder,
     pub(super) mode: Mode,
+    pub(super) modes: Vec<Mode>,
     pub(super) name: String,
     pub(super) skip_on_missing_config: bool,
     pub(super) root: Option<PathBuf>,
+    pub(super) root_resolver: RootResolver,
     pub(super) executor: AnyExecutor,
     pub(super) plugins: PluginsList<C>,
+    pub(super) shutdown_timeout: Duration,
+    pub(super) watch_config: bool,
+    pub(super) concurrent_build: bool,
+    pub(super) report: Option<StartupReport>,
+}
+
+/// Values registered by a concurrently built module, merged once its group is done.
+struct Stage<C> {
+    initializers: Vec<Box<dyn Initializer<C>>>,
+    extensions: Extensions,
+    providers: Providers,
+    named: NamedExtensions,
+    lifecycle: Lifecycle,
+}
+
+impl<C> Default for Stage<C> {
+    fn default() -> Self {
+        Stage {
+            initializers: Vec::default(),
+            extensions: Extensions::default(),
+            providers: Providers::default(),
+            named: NamedExtensions::default(),
+            lifecycle: Lifecycle::default(),
+        }
+    }
+}
+
+/// Reads the optional `enabled` key of a module's config section.
+fn enabled_key(section: &str, config: &Value) -> Result<bool, ConfigError> {
+    let Value::Map(config) = config else {
+        return Ok(true);
+    };
+
+    match config.get("enabled") {
+        None => Ok(true),
+        Some(Value::Bool(enabled)) => Ok(*enabled),
+        Some(value) => Err(ConfigError {
+            section: section.to_string(),
+            path: Some("enabled".to_string()),
+            expected: Some("a boolean".to_string()),
+            message: format!("invalid value {value:?}, expected a boolean"),
+        }),
+    }
+}
+
+/// Groups modules that can be built together: neighbouring modules at the same depth that
+/// are built concurrently. Every other module is its own group.
+fn group_by_depth<T>(builds: impl Iterator<Item = (usize, bool, T)>) -> Vec<Vec<T>> {
+    let mut groups: Vec<Vec<_>> = Vec::default();
+    let mut current = None;
+
+    for (depth, concurrent, build) in builds {
+        match groups.last_mut() {
+            Some(group) if concurrent && current == Some(depth) => group.push(build),
+            _ => groups.push(vec![build]),
+        }
+        current = concurrent.then_some(depth);
+    }
+
+    groups
+}
+
+/// The value of a global flag, which may be disabled
+#[cfg(feature = "cli")]
+fn flag_value<'a>(cli: &'a clap::ArgMatches, id: &str) -> Option<&'a String> {
+    cli.try_get_one::<String>(id).ok().flatten()
+}
+
+impl<C: Context> Build<C> {
+    /// Builds the modules that have a config, marking each one that succeeds in `built`.
+    async fn build_modules(
+        &mut self,
+        root: &Path,
+        configs: Vec<Option<Value>>,
+        lifecycle: &mut Lifecycle,
+        built: &mut [bool],
+    ) -> Result<(), Error> {
+        let builds = self
+            .modules
+            .iter()
+            .zip(&self.depths)
+            .zip(configs)
+            .enumerate()
+            .filter_map(|(idx, ((module, depth), cfg))| {
+                let concurrent = self.concurrent_build && module.concurrent();
+                Some((*depth, concurrent, (idx, &**module, cfg?)))
+            });
+
+        for group in group_by_depth(builds) {
+            if group.len() == 1 {
+                for (idx, module, cfg) in group {
+                    let section = module.config_section();
+                    let build = module.build(
+                        BuildCtx {
+                            ctx: &mut self.ctx,
+                            plugins: &mut self.plugins,
+                            registry: Registry {
+                                module: section,
+                                instance: module.instance(),
+                                initializers: &mut self.initializers,
+                                extensions: &mut self.extensions,
+                                providers: &mut self.providers,
+                                overrides: &self.overrides,
+                                named: &mut self.named,
+                                shared: None,
+                                mode: &self.mode,
+                                root,
+                                lifecycle: &mut *lifecycle,
+                                executor: Some(&self.executor),
+                            },
+                        },
+                        cfg,
+                    );
+
+                    let states = &self.states;
+
+                    report::phase_async(self.report.as_ref(), section, ModulePhase::Build, async {
+                        debug!(module = ?section, "Initializing");
+                        build.await?;
+                        states.complete(section, ModulePhase::Build);
+                        Ok(())
+                    })
+                    .await?;
+
+                    built[idx] = true;
+                }
+                continue;
+            }
+
+            let idxs = group.iter().map(|(idx, _, _)| *idx).collect::<Vec<_>>();
+            let mut stages = group.iter().map(|_| Stage::default()).collect::<Vec<_>>();
+
+            let futures = group
+                .into_iter()
+                .zip(stages.iter_mut())
+                .map(|((_, module, cfg), stage)| {
+                    let section = module.config_section();
+                    let build = module.build_concurrent(
+                        SharedBuildCtx {
+                            ctx: &self.ctx,
+                            registry: Registry {
+                                module: section,
+                                instance: module.instance(),
+                                initializers: &mut stage.initializers,
+                                extensions: &mut stage.extensions,
+                                providers: &mut stage.providers,
+                                overrides: &self.overrides,
+                                named: &mut stage.named,
+                                shared: Some(Shared {
+                                    extensions: &self.extensions,
+                                    providers: &self.providers,
+                                    named: &self.named,
+                                }),
+                                mode: &self.mode,
+                                root,
+                                lifecycle: &mut stage.lifecycle,
+                                executor: Some(&self.executor),
+                            },
+                        },
+                        cfg,
+                    );
+
+                    let states = &self.states;
+
+                    report::phase_async(
+                        self.report.as_ref(),
+                        section,
+                        ModulePhase::Build,
+                        async move {
+                            debug!(module = ?section, "Initializing concurrently");
+                            build.await?;
+                            states.complete(section, ModulePhase::Build);
+                            Ok(())
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let results = join_all(futures).await;
+
+            // Merge in dependency order so the outcome does not depend on which module finished first.
+            // Modules that built are merged even if another one failed, so they can be shut down.
+            let mut failed = None;
+            for ((idx, ret), stage) in idxs.into_iter().zip(results).zip(stages) {
+                match ret {
+                    Ok(()) => {
+                        self.extensions.extend(stage.extensions);
+                        self.providers.extend(stage.providers);
+                        self.named.extend(stage.named);
+                        self.initializers.extend(stage.initializers);
+                        lifecycle.extend(stage.lifecycle);
+                        built[idx] = true;
+                    }
+                    Err(err) => {
+                        failed.get_or_insert(err);
+                    }
+                }
+            }
+
+            if let Some(err) = failed {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves the root directory and loads the config. The config builder is used up.
+    async fn load_config(&mut self) -> Result<(PathBuf, Config, ConfigSources), Error> {
+        let (root, source) = self.root_resolver.resolve(self.root.take())?;
+
+        info!(path = ?root, source = %source, "Root directory");
+
+        let (config, sources) = core::mem::take(&mut self.config)
+            .build(&self.executor, self.mode.clone())
+            .await?;
+
+        debug!(files = ?config.files(), "Using config files");
+
+        Ok((root, config, sources))
+    }
+
+    /// The app as it is after setup, with the config loaded. No module is built.
+    #[cfg(feature = "cli")]
+    pub(super) async fn into_app(mut self) -> Result<crate::Uhuh, Error> {
+        let (root, config, _) = self.load_config().await?;
+
+        Ok(crate::Uhuh {
+            extensions: self.extensions,
+            config: crate::uhuh::SharedConfig::new(config),
+            mode: self.mode,
+            root,
+            name: self.name,
+            providers: self.providers,
+            named: self.named,
+            overrides: self.overrides,
+            states: self.states,
+        })
+    }
 }
 
 impl<C: Context> Phase for Build<C> {
     type Next = Init<C>;
     fn next(mut self) -> impl Future<Output = Result<Self::Next, Error>> {
         async move {
-            let root = match self.root {
-                Some(path) => path,
-                None => std::env::current_dir().map_err(Error::new)?,
-            };
+            let (root, config, sources) = self.load_config().await?;
+
+            let mut errors = Vec::default();
+            let mut active = Vec::with_capacity(self.modules.len());
+            let deps = core::mem::take(&mut self.deps);
+
+            // Dependencies come first, so a disabled dependency is known before its dependents
+            for (module, deps) in self.modules.iter().zip(&deps) {
+                let section = module.config_section();
+
+                if let Some(dep) = deps.iter().find(|dep| !active[**dep]) {
+                    let dependency = self.modules[*dep].config_section();
+                    warn!(module = ?section, dependency = ?dependency, "Dependency disabled. Skipping");
+                    self.states.disable(section, Some(dependency));
+                    if let Some(report) = &self.report {
+                        report.record(
+                            section,
+                            ModulePhase::Build,
+                            Duration::ZERO,
+                            Outcome::Disabled,
+                        );
+                    }
+                    active.push(false);
+                    continue;
+                }
 
-            debug!(path = ?root, "Root directory");
+                let enabled =
+                    match get_section(&config, section).map(|cfg| enabled_key(section, &cfg)) {
+                        Some(Ok(enabled)) => enabled,
+                        Some(Err(err)) => {
+                            errors.push(err);
+                            true
+                        }
+                        None => true,
+                    } && module.is_enabled(&config, &self.mode);
 
-            let config = self.config.build(&self.executor, self.mode.clone()).await?;
+                if !enabled {
+                    debug!(module = ?section, "Module disabled. Skipping");
+                    self.states.disable(section, None);
+                    if let Some(report) = &self.report {
+                        report.record(
+                            section,
+                            ModulePhase::Build,
+                            Duration::ZERO,
+                            Outcome::Disabled,
+                        );
+                    }
+                }
+
+                active.push(enabled);
+            }
 
-            debug!(files = ?config.files(), "Using config files");
+            // Disabled modules are left out of every following phase
+            (self.modules, self.depths) = self
+                .modules
+                .into_iter()
+                .zip(self.depths)
+                .zip(active)
+                .filter_map(|(module, enabled)| enabled.then_some(module))
+                .unzip();
+
+            let mut configs = Vec::with_capacity(self.modules.len());
 
             for module in &self.modules {
-                let Some(cfg) = config.get(module.config_section()) else {
+                let Some(cfg) = get_section(&config, module.config_section()) else {
                     if self.skip_on_missing_config {
                         debug!(module = ?module.config_section(), "Missing config. Skipping");
-                        continue;
+                        self.states.skip(module.config_section());
+                        if let Some(report) = &self.report {
+                            report.record(
+                                module.config_section(),
+                                ModulePhase::Build,
+                                Duration::ZERO,
+                                Outcome::Skipped,
+                            );
+                        }
+                    } else {
+                        errors.push(ConfigError::missing(module.config_section()));
                     }
-
-                    return Err(Error::new(format!(
-                        "config not set for: {}",
-                        module.config_section()
-                    )));
+                    configs.push(None);
+                    continue;
                 };
 
-                debug!(module = ?module.config_section(), "Initializing");
-                module
-                    .build(
-                        BuildCtx {
-                            ctx: &mut self.ctx,
-                            initializers: &mut self.initializers,
-                            extensions: &mut self.extensions,
-                            mode: &self.mode,
-                            root: &*root,
-                            plugins: &mut self.plugins,
-                        },
-                        cfg.clone(),
-                    )
-                    .await?;
+                if let Err(err) = module.validate_config(&cfg) {
+                    errors.push(err);
+                }
+
+                configs.push(Some(cfg));
+            }
+
+            if !errors.is_empty() {
+                return Err(Error::Config(errors));
             }
 
-            self.plugins
-                .build(&mut self.extensions, &root, &self.mode)
-                .await?;
+            let mut lifecycle = Lifecycle::default();
+            let mut built = vec![false; self.modules.len()];
+
+            let ret = match self
+                .build_modules(&root, configs, &mut lifecycle, &mut built)
+                .await
+            {
+                Ok(()) => {
+                    self.plugins.skip_overridden(&self.overrides);
+                    self.plugins
+                        .build(
+                            &mut self.extensions,
+                            &self.providers,
+                            &config,
+                            self.skip_on_missing_config,
+                            &root,
+                            &self.mode,
+                        )
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = ret {
+                let built = self
+                    .modules
+                    .iter()
+                    .zip(&built)
+                    .filter_map(|(module, built)| built.then_some(module));
+                return Err(abort(
+                    err,
+                    built,
+                    &lifecycle,
+                    &self.mode,
+                    &root,
+                    self.shutdown_timeout,
+                )
+                .await);
+            }
 
             Ok(Init {
                 ctx: self.ctx,
                 initializers: self.initializers,
                 extensions: self.extensions,
+                providers: self.providers,
+                overrides: self.overrides,
+                named: self.named,
                 config,
                 mode: self.mode,
                 name: self.name,
                 modules: self.modules,
+                built,
+                states: self.states,
                 root,
+                lifecycle,
+                executor: self.executor,
+                shutdown_timeout: self.shutdown_timeout,
+                config_sources: self.watch_config.then_some(sources),
+                report: self.report,
             })
         }
     }
 }
 
+/// Values registered before a concurrent build started
+pub(super) struct Shared<'a> {
+    pub(super) extensions: &'a Extensions,
+    pub(super) providers: &'a Providers,
+    pub(super) named: &'a NamedExtensions,
+}
+
+/// Where a building module registers values, shared by [`BuildCtx`] and [`SharedBuildCtx`]
+pub(super) struct Registry<'a, C> {
+    pub(super) module: &'a str,
+    pub(super) instance: Option<&'a str>,
+    pub(super) initializers: &'a mut Vec<Box<dyn Initializer<C>>>,
+    pub(super) extensions: &'a mut Extensions,
+    pub(super) providers: &'a mut Providers,
+    pub(super) overrides: &'a Overrides,
+    pub(super) named: &'a mut NamedExtensions,
+    /// Set while modules build concurrently
+    pub(super) shared: Option<Shared<'a>>,
+    pub(super) mode: &'a Mode,
+    pub(super) root: &'a Path,
+    pub(super) lifecycle: &'a mut Lifecycle,
+    /// Not set in the test harness
+    pub(super) executor: Option<&'a AnyExecutor>,
+}
+
+impl<'a, C> Registry<'a, C> {
+    fn reborrow(&mut self) -> Registry<'_, C> {
+        Registry {
+            module: self.module,
+            instance: self.instance,
+            initializers: &mut *self.initializers,
+            extensions: &mut *self.extensions,
+            providers: &mut *self.providers,
+            overrides: self.overrides,
+            named: &mut *self.named,
+            shared: self.shared.as_ref().map(|shared| Shared {
+                extensions: shared.extensions,
+                providers: shared.providers,
+                named: shared.named,
+            }),
+            mode: self.mode,
+            root: self.root,
+            lifecycle: &mut *self.lifecycle,
+            executor: self.executor,
+        }
+    }
+
+    fn register<T: Send + Sync + 'static>(&mut self, value: T) {
+        if self.overrides.ignores::<T>(self.module) {
+            return;
+        }
+
+        match self.instance {
+            Some(name) => self.named.insert(name, value),
+            None => {
+                self.providers.insert::<T>(self.module);
+                self.extensions.insert(value);
+            }
+        }
+    }
+
+    fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        if let Some(value) = self.instance.and_then(|name| self.named.get::<T>(name)) {
+            return Some(value);
+        }
+
+        self.extensions.get::<T>().or_else(|| {
+            self.shared
+                .as_ref()
+                .and_then(|shared| shared.extensions.get::<T>())
+        })
+    }
+
+    fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.named.get::<T>(name).or_else(|| {
+            self.shared
+                .as_ref()
+                .and_then(|shared| shared.named.get::<T>(name))
+        })
+    }
+
+    fn require<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        self.get::<T>().ok_or_else(|| {
+            let provider = self.providers.get::<T>().or_else(|| {
+                self.shared
+                    .as_ref()
+                    .and_then(|shared| shared.providers.get::<T>())
+            });
+            MissingExtension::new::<T>().with_provider(provider).into()
+        })
+    }
+
+    fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        if self.overrides.locks::<T>(self.module) {
+            return None;
+        }
+
+        match self.instance {
+            Some(name) if self.named.get::<T>(name).is_some() => self.named.get_mut::<T>(name),
+            _ => self.extensions.get_mut::<T>(),
+        }
+    }
+}
+
+/// The context of a module build, with exclusive access to the context and the plugins.
 pub struct BuildCtx<'a, C> {
-    ctx: &'a mut C,
-    initializers: &'a mut Vec<Box<dyn Initializer<C>>>,
-    extensions: &'a mut Extensions,
-    mode: &'a Mode,
-    root: &'a Path,
-    plugins: &'a mut PluginsList<C>,
+    pub(super) ctx: &'a mut C,
+    pub(super) plugins: &'a mut PluginsList<C>,
+    pub(super) registry: Registry<'a, C>,
 }
 
 impl<'a, C> BuildCtx<'a, C> {
     pub fn mode(&self) -> &Mode {
-        self.mode
+        self.registry.mode
     }
 
     pub fn root(&self) -> &Path {
-        self.root
+        self.registry.root
     }
 
     pub fn add_initializer<T: Initializer<C> + 'static>(&mut self, init: T) -> &mut Self {
-        self.initializers.push(Box::new(init));
+        self.registry.initializers.push(Box::new(init));
         self
     }
 
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.registry.instance
+    }
+
+    /// Register a value. Values registered by a named instance are stored under
+    /// the instance name, see [`get_named`](Self::get_named).
     pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
-        self.extensions.insert(value);
+        self.registry.register(value);
         self
     }
 
+    /// Register a value for the module lifecycle hooks, like a pool that must be closed
+    /// on shutdown. It is available from [`ShutdownCtx::get`](super::ShutdownCtx::get)
+    /// and [`ReconfigureCtx::get`](super::ReconfigureCtx::get).
+    /// Values registered by a named instance are only visible to that instance.
+    pub fn register_lifecycle<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.registry
+            .lifecycle
+            .insert(self.registry.instance, value);
+        self
+    }
+
+    /// Get a value. A named instance sees the values it registered itself first.
     pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
-        self.extensions.get::<T>()
+        self.registry.get::<T>()
+    }
+
+    /// Get a value registered by the named module instance `name`
+    pub fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.registry.get_named::<T>(name)
     }
 
+    /// Like [`get`](Self::get), but fails with an error naming the type
+    /// and the module expected to register it.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        self.registry.require::<T>()
+    }
+
+    /// Get a value mutably. Values pinned with `override_ext` can not be changed, so this returns `None` for them.
     pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
-        self.extensions.get_mut::<T>()
+        self.registry.get_mut::<T>()
     }
 
     pub fn plugin<T>(&mut self) -> Result<&mut T, Error>
@@ -230,6 +803,15 @@ impl<'a, C> BuildCtx<'a, C> {
     {
         self.plugins.get_mut()
     }
+
+    /// A view without mutable access to the context, as passed to
+    /// [`ConcurrentModule::build_concurrent`](crate::ConcurrentModule::build_concurrent).
+    pub fn shared(&mut self) -> SharedBuildCtx<'_, C> {
+        SharedBuildCtx {
+            ctx: &*self.ctx,
+            registry: self.registry.reborrow(),
+        }
+    }
 }
 
 impl<'a, C> core::ops::Deref for BuildCtx<'a, C> {
@@ -244,3 +826,391 @@ impl<'a, C> core::ops::DerefMut for BuildCtx<'a, C> {
         self.ctx
     }
 }
+
+/// The context of a [`ConcurrentModule`](crate::ConcurrentModule) build.
+///
+/// The context is shared with the other modules building at the same time, so it can
+/// only be borrowed immutably, and plugins can not be configured. Values registered by
+/// the other modules in the group are not visible until the group has finished.
+pub struct SharedBuildCtx<'a, C> {
+    pub(super) ctx: &'a C,
+    pub(super) registry: Registry<'a, C>,
+}
+
+impl<'a, C> SharedBuildCtx<'a, C> {
+    pub fn mode(&self) -> &Mode {
+        self.registry.mode
+    }
+
+    pub fn root(&self) -> &Path {
+        self.registry.root
+    }
+
+    pub fn add_initializer<T: Initializer<C> + 'static>(&mut self, init: T) -> &mut Self {
+        self.registry.initializers.push(Box::new(init));
+        self
+    }
+
+    /// The module's config section
+    pub fn module(&self) -> &str {
+        self.registry.module
+    }
+
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.registry.instance
+    }
+
+    /// Register a value, see [`BuildCtx::register`]
+    pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.registry.register(value);
+        self
+    }
+
+    /// Register a value for the module lifecycle hooks, see [`BuildCtx::register_lifecycle`]
+    pub fn register_lifecycle<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.registry
+            .lifecycle
+            .insert(self.registry.instance, value);
+        self
+    }
+
+    /// Get a value. A named instance sees the values it registered itself first.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.registry.get::<T>()
+    }
+
+    /// Get a value registered by the named module instance `name`
+    pub fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.registry.get_named::<T>(name)
+    }
+
+    /// Like [`get`](Self::get), but fails with an error naming the type
+    /// and the module expected to register it.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        self.registry.require::<T>()
+    }
+
+    /// Only values registered by this module can be borrowed mutably.
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.registry.get_mut::<T>()
+    }
+
+    /// Run blocking or CPU-heavy work on the executor, so the other modules in the
+    /// group keep building. Without an executor, like in the test harness, `func`
+    /// runs on the current thread.
+    pub async fn spawn_blocking<F, O>(&self, func: F) -> Result<O, Error>
+    where
+        F: FnOnce() -> O + Send + 'static,
+        O: Send + 'static,
+    {
+        match self.registry.executor {
+            Some(executor) => executor
+                .spawn_blocking(func)
+                .into_future()
+                .await
+                .map_err(Error::new),
+            None => Ok(func()),
+        }
+    }
+}
+
+impl<'a, C> core::ops::Deref for SharedBuildCtx<'a, C> {
+    type Target = C;
+    fn deref(&self) -> &Self::Target {
+        self.ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConcurrentModule, Module};
+    use bobestyrer::Tokio;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn groups_concurrent_modules_at_the_same_depth() {
+        let builds = [
+            (0, true, "a"),
+            (0, true, "b"),
+            (0, false, "c"),
+            (0, true, "d"),
+            (1, true, "e"),
+            (1, true, "f"),
+        ];
+
+        assert_eq!(
+            group_by_depth(builds.into_iter()),
+            vec![vec!["a", "b"], vec!["c"], vec!["d"], vec!["e", "f"]]
+        );
+    }
+
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Completes once both modules have started building, which only happens if they overlap
+    async fn rendezvous() -> Result<(), Error> {
+        STARTED.fetch_add(1, Ordering::SeqCst);
+        for _ in 0..1000 {
+            if STARTED.load(Ordering::SeqCst) >= 2 {
+                return Ok(());
+            }
+            tokio::task::yield_now().await;
+        }
+        Err(Error::new("modules did not build concurrently"))
+    }
+
+    #[derive(Default, serde::Serialize, serde::Deserialize)]
+    struct Empty {}
+
+    struct First;
+
+    impl Module<()> for First {
+        const CONFIG_SECTION: &'static str = "first";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, config: Empty) -> Result<(), Error> {
+            Self::build_concurrent(ctx.shared(), config).await
+        }
+    }
+
+    impl ConcurrentModule<()> for First {
+        async fn build_concurrent(mut ctx: SharedBuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            rendezvous().await?;
+            let value = ctx.spawn_blocking(|| 1u8).await?;
+            ctx.register(value);
+            Ok(())
+        }
+    }
+
+    struct Second;
+
+    impl Module<()> for Second {
+        const CONFIG_SECTION: &'static str = "second";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, config: Empty) -> Result<(), Error> {
+            Self::build_concurrent(ctx.shared(), config).await
+        }
+    }
+
+    impl ConcurrentModule<()> for Second {
+        async fn build_concurrent(mut ctx: SharedBuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            rendezvous().await?;
+            ctx.register(2u16);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn independent_modules_build_concurrently() {
+        let (app, _) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .concurrent_build(true)
+            .concurrent_module::<First>()
+            .concurrent_module::<Second>()
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(app.get::<u8>(), Some(&1));
+        assert_eq!(app.get::<u16>(), Some(&2));
+    }
+
+    struct Base;
+
+    impl Module<()> for Base {
+        const CONFIG_SECTION: &'static str = "base";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        fn is_enabled(_: &johnfig::Config, _: &Mode) -> bool {
+            false
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            ctx.register(1u32);
+            Ok(())
+        }
+    }
+
+    struct Dependent;
+
+    impl Module<()> for Dependent {
+        const CONFIG_SECTION: &'static str = "dependent";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        fn setup(mut ctx: crate::SetupCtx<'_, ()>) -> Result<(), Error> {
+            ctx.depends_on::<Base>()?;
+            Ok(())
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            ctx.register(2u64);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dependents_of_disabled_modules_are_disabled() {
+        let (app, _) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .module::<Dependent>()
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(app.get::<u32>(), None);
+        assert_eq!(app.get::<u64>(), None);
+
+        let modules = app.modules();
+        let dependent = modules.iter().find(|m| m.section == "dependent").unwrap();
+        assert!(dependent.disabled);
+        assert_eq!(dependent.disabled_by.as_deref(), Some("base"));
+    }
+
+    #[test]
+    fn reads_the_enabled_key() {
+        let value = |json| vaerdi::ser::to_value(json).unwrap();
+
+        assert!(enabled_key("a", &value(serde_json::json!({}))).unwrap());
+        assert!(!enabled_key("a", &value(serde_json::json!({ "enabled": false }))).unwrap());
+        assert!(enabled_key("a", &value(serde_json::json!("plain"))).unwrap());
+
+        let err = enabled_key("a", &value(serde_json::json!({ "enabled": "no" }))).unwrap_err();
+        assert_eq!(err.path.as_deref(), Some("enabled"));
+    }
+
+    struct Tamper;
+
+    impl Module<()> for Tamper {
+        const CONFIG_SECTION: &'static str = "tamper";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            ctx.register(1u8);
+            if let Some(value) = ctx.get_mut::<u8>() {
+                *value = 2;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn overridden_values_can_not_be_replaced() {
+        let (mut app, _) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .override_ext(0u8)
+            .module::<Tamper>()
+            .module_named::<Tamper>("tamper2")
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(app.get::<u8>(), Some(&0));
+        assert_eq!(app.get_named::<u8>("tamper2"), None);
+
+        app.register(3u8);
+        assert_eq!(app.get_mut::<u8>(), None);
+        assert_eq!(app.get::<u8>(), Some(&0));
+    }
+
+    struct SetupTamper;
+
+    impl Module<()> for SetupTamper {
+        const CONFIG_SECTION: &'static str = "setup_tamper";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        fn setup(mut ctx: crate::SetupCtx<'_, ()>) -> Result<(), Error> {
+            ctx.register(1u16);
+            Ok(())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn named_instances_can_not_replace_overridden_values_during_setup() {
+        let (app, _) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .override_ext(0u16)
+            .module_named::<SetupTamper>("setup_tamper2")
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(app.get::<u16>(), Some(&0));
+        assert_eq!(app.get_named::<u16>("setup_tamper2"), None);
+    }
+
+    #[cfg(feature = "cli")]
+    static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+    #[cfg(feature = "cli")]
+    struct Closer;
+
+    #[cfg(feature = "cli")]
+    impl Module<()> for Closer {
+        const CONFIG_SECTION: &'static str = "closer";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(mut ctx: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            ctx.register(1i8);
+            Ok(())
+        }
+
+        async fn shutdown(_: crate::ShutdownCtx<'_>) -> Result<(), Error> {
+            CLOSED.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[tokio::test]
+    async fn built_modules_are_shut_down_after_build_commands() {
+        let builder = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .module::<Closer>()
+            .setup()
+            .await
+            .unwrap();
+
+        let (app, shutdown) = builder.phase.next().await.unwrap().into_app();
+        assert_eq!(app.get::<i8>(), Some(&1));
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 0);
+
+        shutdown.run().await.unwrap();
+        assert_eq!(CLOSED.load(Ordering::SeqCst), 1);
+    }
+}
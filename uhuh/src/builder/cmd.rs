@@ -3,9 +3,24 @@ use std::marker::PhantomData;
 
 use crate::{context::Context, Error};
 
+pub(crate) type BoxAction<C> =
+    Box<dyn CmdAction<C, Future = LocalBoxFuture<'static, Result<(), Error>>>>;
+
 pub struct Cmd<C> {
+    /// The full subcommand path, like `["db", "migrate"]`
+    pub(crate) path: Vec<String>,
     pub(crate) cmd: clap::Command,
-    pub(crate) action: Box<dyn CmdAction<C, Future = LocalBoxFuture<'static, Result<(), Error>>>>,
+    pub(crate) action: CmdRun<C>,
+}
+
+/// How far the app is built before a command runs
+pub(crate) enum CmdRun<C> {
+    /// After setup, with the config loaded. No module is built.
+    Setup(BoxAction<()>),
+    /// After all modules are built, before any is initialized
+    Build(BoxAction<()>),
+    /// With the fully initialized app
+    Init(BoxAction<C>),
 }
 
 pub trait CmdAction<C: Context> {
@@ -25,9 +40,7 @@ where
     }
 }
 
-pub fn box_action<C, T>(
-    action: T,
-) -> Box<dyn CmdAction<C, Future = LocalBoxFuture<'static, Result<(), Error>>>>
+pub fn box_action<C, T>(action: T) -> BoxAction<C>
 where
     T: CmdAction<C> + 'static,
     C: Context + 'static,
@@ -48,3 +61,111 @@ where
 
     Box::new(Impl(Box::new(action), PhantomData))
 }
+
+/// Adds `cmd` below the groups in `parents`, creating the groups that do not exist yet.
+pub(crate) fn insert_cmd(
+    parent: clap::Command,
+    parents: &[String],
+    cmd: clap::Command,
+) -> clap::Command {
+    let Some((group, rest)) = parents.split_first() else {
+        return parent.subcommand(cmd);
+    };
+
+    let parent = if parent.find_subcommand(group).is_none() {
+        parent.subcommand(
+            clap::Command::new(group.clone())
+                .subcommand_required(true)
+                .arg_required_else_help(true),
+        )
+    } else {
+        parent
+    };
+
+    parent.mut_subcommand(group, |group| insert_cmd(group, rest, cmd))
+}
+
+/// Follows the matched subcommands and returns the index of the deepest registered
+/// command on the path, with its matches. A command may handle its own subcommands.
+pub(crate) fn find_cmd<C>(
+    cmds: &[Cmd<C>],
+    matches: &clap::ArgMatches,
+) -> Option<(usize, clap::ArgMatches)> {
+    let mut path = Vec::default();
+    let mut matches = matches;
+    let mut found = None;
+
+    while let Some((name, args)) = matches.subcommand() {
+        path.push(name);
+        matches = args;
+
+        if let Some(idx) = cmds.iter().position(|cmd| cmd.path == path) {
+            found = Some((idx, args.clone()));
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(path: &[&str]) -> Cmd<()> {
+        Cmd {
+            path: path.iter().map(|m| m.to_string()).collect(),
+            cmd: clap::Command::new(path[path.len() - 1]),
+            action: CmdRun::Setup(box_action(|_: crate::Uhuh, _: clap::ArgMatches| async {
+                Ok::<_, Error>(())
+            })),
+        }
+    }
+
+    fn app(cmds: &[Cmd<()>]) -> clap::Command {
+        cmds.iter().fold(clap::Command::new("app"), |app, cmd| {
+            insert_cmd(app, &cmd.path[..cmd.path.len() - 1], cmd.cmd.clone())
+        })
+    }
+
+    fn found(cmds: &[Cmd<()>], args: &[&str]) -> Option<usize> {
+        let matches = app(cmds).try_get_matches_from(args).ok()?;
+        find_cmd(cmds, &matches).map(|(idx, _)| idx)
+    }
+
+    #[test]
+    fn insert_cmd_creates_groups() {
+        let app = app(&[cmd(&["db", "migrate"]), cmd(&["db", "seed", "users"])]);
+
+        let db = app.find_subcommand("db").unwrap();
+        assert!(db.find_subcommand("migrate").is_some());
+        assert!(db
+            .find_subcommand("seed")
+            .and_then(|seed| seed.find_subcommand("users"))
+            .is_some());
+    }
+
+    #[test]
+    fn insert_cmd_keeps_existing_groups() {
+        let app = app(&[cmd(&["db", "migrate"]), cmd(&["db", "reset"])]);
+
+        let db = app.find_subcommand("db").unwrap();
+        assert_eq!(db.get_subcommands().count(), 2);
+    }
+
+    #[test]
+    fn find_cmd_returns_the_deepest_command() {
+        let cmds = [cmd(&["db", "migrate"]), cmd(&["db", "seed", "users"])];
+
+        assert_eq!(found(&cmds, &["app", "db", "migrate"]), Some(0));
+        assert_eq!(found(&cmds, &["app", "db", "seed", "users"]), Some(1));
+    }
+
+    #[test]
+    fn find_cmd_ignores_groups() {
+        let cmds = [cmd(&["db", "migrate"])];
+        let app = app(&cmds).subcommand(clap::Command::new("other"));
+        let matches = app.try_get_matches_from(["app", "other"]).unwrap();
+
+        assert!(find_cmd(&cmds, &matches).is_none());
+    }
+}
@@ -1,17 +1,29 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use bobestyrer::{AnyExecutor, Executor, JoinHandle};
 use johnfig::Config;
 use toback::Toback;
 use tracing::{debug, warn};
+use vaerdi::Value;
 
-use crate::{Configure, Error, Mode};
+use crate::{
+    error::config_source_error,
+    secrets::{SecretProvider, SecretProviders},
+    ConfigError, Configure, Error, Mode,
+};
 
 #[derive(Default)]
 pub struct ConfigBuilder {
     files: Vec<PathBuf>,
     builder: Option<johnfig::ConfigBuilder>,
+    patterns: Vec<String>,
     configures: Vec<Box<dyn Configure + Send>>,
+    env_prefix: Option<String>,
+    env_separator: Option<String>,
+    secrets: SecretProviders,
 }
 
 impl ConfigBuilder {
@@ -33,60 +45,467 @@ impl ConfigBuilder {
         self
     }
 
+    /// Add a file name pattern like `config.{ext}`.
+    /// A pattern ending in `.{ext}` is also matched as `config.{mode}.{ext}`,
+    /// and those files are merged on top of the base files.
     pub fn add_filename_pattern(&mut self, pattern: String) -> &mut Self {
-        self.builder
-            .get_or_insert_with(johnfig::ConfigBuilder::new)
-            .add_name_pattern(pattern);
+        self.patterns.push(pattern);
+        self
+    }
+
+    /// Override config keys from environment variables starting with `prefix`.
+    /// With the prefix `APP`, `APP__DATABASE__URL` sets `database.url`.
+    pub fn set_env_prefix(&mut self, prefix: String) -> &mut Self {
+        self.env_prefix = Some(prefix);
+        self
+    }
+
+    /// Separator between the prefix and nested keys in environment variables. Defaults to `__`.
+    pub fn set_env_separator(&mut self, separator: String) -> &mut Self {
+        self.env_separator = Some(separator);
+        self
+    }
+
+    /// Resolve `${scheme:key}` references in config strings with `provider`.
+    /// Replaces any provider registered for the same scheme.
+    pub fn add_secret_provider(
+        &mut self,
+        scheme: &str,
+        provider: Arc<dyn SecretProvider>,
+    ) -> &mut Self {
+        self.secrets.insert(scheme, provider);
         self
     }
 
-    pub async fn build(self, executor: &AnyExecutor, mode: Mode) -> Result<Config, Error> {
+    pub async fn build(
+        self,
+        executor: &AnyExecutor,
+        mode: Mode,
+    ) -> Result<(Config, ConfigSources), Error> {
         executor
             .spawn_blocking(move || {
-                let mut config = Config::default();
+                let mut base = Config::default();
 
                 for cfg in self.configures {
-                    cfg.call(&mut config)?;
+                    cfg.call(&mut base)?;
                 }
 
-                if let Some(builder) = self.builder {
+                let mut config = base.clone();
+                let mut files = Vec::default();
+
+                let builder = if self.patterns.is_empty() {
+                    self.builder
+                } else {
+                    Some(self.builder.unwrap_or_else(johnfig::ConfigBuilder::new))
+                };
+
+                if let Some(mut builder) = builder {
+                    let mode_patterns = self
+                        .patterns
+                        .iter()
+                        .filter_map(|pattern| mode_pattern(pattern.as_str()));
+                    for pattern in self.patterns.iter().cloned().chain(mode_patterns) {
+                        builder.add_name_pattern(pattern);
+                    }
+
+                    let template_mode = mode.clone();
                     let cfg = builder
                         .build_with(move |ext| {
                             vaerdi::value!({
                                 "ext": ext,
-                                "mode": mode.clone()
+                                "mode": template_mode.clone()
                             })
                         })
                         .and_then(|m| m.config())
-                        .map_err(Error::new)?;
+                        .map_err(|err| config_source_error("config", err))?;
 
+                    files.extend(cfg.files().iter().map(|path| path.to_path_buf()));
                     config.extend(cfg);
                 }
 
                 let encoder = Toback::<Config>::new();
 
-                for path in self.files {
-                    if !path.is_file() {
-                        debug!(path = ?path, "Path not a file. Skipping");
-                        continue;
-                    }
-
-                    let Some(encoder) = encoder.encoder_from_path(&path) else {
-                        warn!(path = ?path, "Could not find a decoder for path. Skipping");
-                        continue;
-                    };
+                let explicit = self.files.into_iter().flat_map(|path| {
+                    let mode_path = mode_file(&path, &mode);
+                    std::iter::once(path).chain(mode_path)
+                });
 
-                    let ret = std::fs::read(path).map_err(Error::new)?;
+                for path in explicit {
+                    if load_file(&encoder, &path, &mut config)? {
+                        files.push(path);
+                    }
+                }
 
-                    let cfg = encoder.load(&ret).map_err(Error::new)?;
+                let mut sources = ConfigSources {
+                    base,
+                    files,
+                    env_prefix: self.env_prefix,
+                    env_separator: self.env_separator.unwrap_or_else(|| "__".to_string()),
+                    secrets: self.secrets,
+                    interpolated: Vec::default(),
+                };
 
-                    config.extend(cfg);
-                }
+                sources.apply_env(&mut config)?;
+                sources.interpolated = sources.secrets.interpolate(&mut config)?;
 
-                Result::<_, Error>::Ok(config)
+                Result::<_, Error>::Ok((config, sources))
             })
             .into_future()
             .await
             .map_err(Error::new)?
     }
 }
+
+/// The sources a config was merged from, kept so it can be reloaded.
+///
+/// `Configure` callbacks only run once, so their result is kept as the base
+/// that files and the environment are merged on top of.
+#[derive(Clone)]
+pub(crate) struct ConfigSources {
+    base: Config,
+    files: Vec<PathBuf>,
+    env_prefix: Option<String>,
+    env_separator: String,
+    secrets: SecretProviders,
+    /// Paths of the values a secret reference was resolved in when the config was built
+    #[cfg_attr(not(feature = "cli"), allow(unused))]
+    interpolated: Vec<Vec<String>>,
+}
+
+impl ConfigSources {
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn interpolated(&self) -> &[Vec<String>] {
+        &self.interpolated
+    }
+
+    pub fn load(&self) -> Result<Config, Error> {
+        let mut config = self.base.clone();
+        let encoder = Toback::<Config>::new();
+
+        for path in &self.files {
+            load_file(&encoder, path, &mut config)?;
+        }
+
+        self.apply_env(&mut config)?;
+        self.secrets.interpolate(&mut config)?;
+
+        Ok(config)
+    }
+
+    fn apply_env(&self, config: &mut Config) -> Result<(), Error> {
+        match &self.env_prefix {
+            Some(prefix) => apply_env(config, prefix, &self.env_separator),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Merges the file at `path` into `config`. Returns `false` if the file was skipped.
+fn load_file(encoder: &Toback<Config>, path: &Path, config: &mut Config) -> Result<bool, Error> {
+    if !path.is_file() {
+        debug!(path = ?path, "Path not a file. Skipping");
+        return Ok(false);
+    }
+
+    let Some(encoder) = encoder.encoder_from_path(path) else {
+        warn!(path = ?path, "Could not find a decoder for path. Skipping");
+        return Ok(false);
+    };
+
+    let ret = std::fs::read(path).map_err(Error::new)?;
+
+    let cfg = encoder
+        .load(&ret)
+        .map_err(|err| ConfigError::load(path.display(), err))?;
+
+    config.extend(cfg);
+
+    Ok(true)
+}
+
+/// Gets a config section. Sections of named module instances may be nested, like `db.replica`.
+pub(crate) fn get_section(config: &Config, path: &str) -> Option<Value> {
+    let Some((root, rest)) = path.split_once('.') else {
+        return config.get(path).cloned();
+    };
+
+    let mut value = serde_json::to_value(config.get(root)?).ok()?;
+    for key in rest.split('.') {
+        value = value.get_mut(key)?.take();
+    }
+
+    vaerdi::ser::to_value(value).ok()
+}
+
+/// Sets a config section, merging nested sections into their parent.
+pub fn set_section(config: &mut Config, path: &str, value: Value) -> Result<(), Error> {
+    let Some((root, rest)) = path.split_once('.') else {
+        config.set(path, value);
+        return Ok(());
+    };
+
+    let mut current = match config.get(root) {
+        Some(current) => serde_json::to_value(current).map_err(Error::new)?,
+        None => serde_json::Value::Null,
+    };
+
+    let rest = rest.split('.').map(|m| m.to_string()).collect::<Vec<_>>();
+    set_path(
+        &mut current,
+        &rest,
+        serde_json::to_value(&value).map_err(Error::new)?,
+    );
+
+    config.set(root, vaerdi::ser::to_value(current)?);
+
+    Ok(())
+}
+
+fn mode_pattern(pattern: &str) -> Option<String> {
+    if pattern.contains("{mode}") {
+        return None;
+    }
+
+    pattern
+        .strip_suffix(".{ext}")
+        .map(|base| format!("{base}.{{mode}}.{{ext}}"))
+}
+
+/// `config/app.yaml` becomes `config/app.{mode}.yaml`, named like the files matched by patterns
+fn mode_file(path: &Path, mode: &Mode) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_str()?;
+    let ext = path.extension()?.to_str()?;
+    Some(path.with_file_name(format!("{stem}.{}.{ext}", mode.as_str())))
+}
+
+fn apply_env(config: &mut Config, prefix: &str, separator: &str) -> Result<(), Error> {
+    let prefix = format!("{prefix}{separator}");
+
+    let mut vars = std::env::vars()
+        .filter_map(|(key, value)| {
+            let path = key
+                .strip_prefix(&prefix)?
+                .split(separator)
+                .map(|part| part.to_string())
+                .collect::<Vec<_>>();
+            Some((path, value))
+        })
+        .collect::<Vec<_>>();
+
+    // Apply shorter paths first so `APP__DB` does not clobber `APP__DB__URL`
+    vars.sort();
+
+    let sections = match serde_json::to_value(&*config).map_err(Error::new)? {
+        serde_json::Value::Object(map) => map.keys().cloned().collect(),
+        _ => Vec::default(),
+    };
+
+    for (path, value) in vars {
+        let Some((section, rest)) = path.split_first() else {
+            continue;
+        };
+
+        if path.iter().any(|part| part.is_empty()) {
+            warn!(key = ?path.join("."), "Invalid config key in environment. Skipping");
+            continue;
+        }
+
+        debug!(key = ?path.join("."), "Setting config from environment");
+
+        let section = find_key(&sections, section).unwrap_or_else(|| section.to_lowercase());
+
+        // Merge into the existing section instead of replacing it
+        let mut current = match config.get(&section) {
+            Some(current) => serde_json::to_value(current).map_err(Error::new)?,
+            None => serde_json::Value::Null,
+        };
+
+        set_env_path(&mut current, rest, &value);
+
+        config.set(&section, vaerdi::ser::to_value(current)?);
+    }
+
+    Ok(())
+}
+
+/// Like [`set_path`], but environment keys are matched against existing keys ignoring case,
+/// so `APP__HTTP__MAXCONNECTIONS` sets `http.maxConnections`. New keys are lowercased.
+fn set_env_path(target: &mut serde_json::Value, path: &[String], value: &str) {
+    let Some((key, rest)) = path.split_first() else {
+        *target = coerce(value, target);
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::default());
+    }
+
+    if let Some(map) = target.as_object_mut() {
+        let key = find_key(map.keys(), key).unwrap_or_else(|| key.to_lowercase());
+        let child = map.entry(key).or_insert(serde_json::Value::Null);
+        set_env_path(child, rest, value);
+    }
+}
+
+fn find_key<'a>(keys: impl IntoIterator<Item = &'a String>, key: &str) -> Option<String> {
+    keys.into_iter()
+        .find(|candidate| candidate.eq_ignore_ascii_case(key))
+        .cloned()
+}
+
+pub(super) fn set_path(target: &mut serde_json::Value, path: &[String], value: serde_json::Value) {
+    let Some((key, rest)) = path.split_first() else {
+        *target = value;
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::default());
+    }
+
+    if let Some(map) = target.as_object_mut() {
+        let child = map.entry(key.clone()).or_insert(serde_json::Value::Null);
+        set_path(child, rest, value);
+    }
+}
+
+/// Parses an environment value as the type of `current`, the value it replaces.
+/// Strings stay strings. Otherwise booleans, integers, floats and JSON arrays or objects
+/// are parsed, and anything else is kept as a string.
+fn coerce(value: &str, current: &serde_json::Value) -> serde_json::Value {
+    if current.is_string() {
+        return serde_json::Value::String(value.to_string());
+    }
+
+    let trimmed = value.trim();
+
+    if trimmed.eq_ignore_ascii_case("true") {
+        return serde_json::Value::Bool(true);
+    } else if trimmed.eq_ignore_ascii_case("false") {
+        return serde_json::Value::Bool(false);
+    }
+
+    // Numbers like `007` would lose their formatting, so they stay strings
+    if let Ok(int) = trimmed.parse::<i64>() {
+        if int.to_string() == trimmed {
+            return int.into();
+        }
+        return serde_json::Value::String(value.to_string());
+    }
+
+    if let Some(float) = trimmed
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        return serde_json::Value::Number(float);
+    }
+
+    if trimmed.starts_with('{') || trimmed.starts_with('[') {
+        if let Ok(json) = serde_json::from_str(trimmed) {
+            return json;
+        }
+    }
+
+    serde_json::Value::String(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(value: serde_json::Value) -> Config {
+        let mut config = Config::default();
+        if let serde_json::Value::Object(map) = value {
+            for (key, value) in map {
+                config.set(&key, vaerdi::ser::to_value(value).unwrap());
+            }
+        }
+        config
+    }
+
+    #[test]
+    fn mode_patterns() {
+        assert_eq!(
+            mode_pattern("config.{ext}").as_deref(),
+            Some("config.{mode}.{ext}")
+        );
+        assert_eq!(
+            mode_pattern("app/settings.{ext}").as_deref(),
+            Some("app/settings.{mode}.{ext}")
+        );
+        assert_eq!(mode_pattern("config.{mode}.{ext}"), None);
+        assert_eq!(mode_pattern("config.yaml"), None);
+    }
+
+    #[test]
+    fn mode_files() {
+        assert_eq!(
+            mode_file(Path::new("config/app.yaml"), &Mode::Development),
+            Some(PathBuf::from("config/app.development.yaml"))
+        );
+        assert_eq!(
+            mode_file(Path::new("app.toml"), &Mode::custom("Staging").unwrap()),
+            Some(PathBuf::from("app.staging.toml"))
+        );
+        assert_eq!(mode_file(Path::new("config"), &Mode::Production), None);
+    }
+
+    #[test]
+    fn coerce_parses_non_string_targets() {
+        let null = serde_json::Value::Null;
+        assert_eq!(coerce("true", &null), json!(true));
+        assert_eq!(coerce("FALSE", &null), json!(false));
+        assert_eq!(coerce("42", &null), json!(42));
+        assert_eq!(coerce("1.5", &null), json!(1.5));
+        assert_eq!(coerce("[1, 2]", &null), json!([1, 2]));
+        assert_eq!(coerce("{\"a\": 1}", &null), json!({ "a": 1 }));
+        assert_eq!(coerce("hello", &null), json!("hello"));
+        assert_eq!(coerce("8080", &json!(80)), json!(8080));
+    }
+
+    #[test]
+    fn coerce_keeps_strings() {
+        assert_eq!(coerce("007", &json!("000")), json!("007"));
+        assert_eq!(coerce("true", &json!("yes")), json!("true"));
+        assert_eq!(coerce("007", &serde_json::Value::Null), json!("007"));
+        assert_eq!(coerce("+5", &serde_json::Value::Null), json!("+5"));
+    }
+
+    #[test]
+    fn apply_env_sets_nested_keys() {
+        std::env::set_var("UHUH_TEST_NESTED__DB__POOL__SIZE", "10");
+        std::env::set_var("UHUH_TEST_NESTED__DB__URL", "postgres://localhost");
+        std::env::set_var("UHUH_TEST_NESTED__CACHE__TTL", "60");
+
+        let mut cfg = config(json!({ "db": { "url": "sqlite://", "name": "app" } }));
+        apply_env(&mut cfg, "UHUH_TEST_NESTED", "__").unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&cfg).unwrap(),
+            json!({
+                "db": { "url": "postgres://localhost", "name": "app", "pool": { "size": 10 } },
+                "cache": { "ttl": 60 },
+            })
+        );
+    }
+
+    #[test]
+    fn apply_env_matches_existing_keys_ignoring_case() {
+        std::env::set_var("UHUH_TEST_CASE__HTTP__MAXCONNECTIONS", "100");
+        std::env::set_var("UHUH_TEST_CASE__HTTP__APIKEY", "0123");
+
+        let mut cfg = config(json!({ "Http": { "maxConnections": 10, "apiKey": "abc" } }));
+        apply_env(&mut cfg, "UHUH_TEST_CASE", "__").unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&cfg).unwrap(),
+            json!({ "Http": { "maxConnections": 100, "apiKey": "0123" } })
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fmt, sync::Arc};
+
+use tracing::level_filters::LevelFilter;
+
+use crate::Error;
+
+/// A global command line flag
+#[derive(Debug, Clone)]
+pub struct Flag {
+    long: String,
+    short: Option<char>,
+}
+
+impl Flag {
+    pub fn long(name: impl ToString) -> Flag {
+        Flag {
+            long: name.to_string(),
+            short: None,
+        }
+    }
+
+    pub fn short(mut self, short: char) -> Flag {
+        self.short = Some(short);
+        self
+    }
+
+    fn arg(&self, id: &'static str) -> clap::Arg {
+        let arg = clap::Arg::new(id).long(self.long.clone());
+        match self.short {
+            Some(short) => arg.short(short),
+            None => arg,
+        }
+    }
+
+    /// Whether `arg` uses the same short or long name
+    fn clashes(&self, arg: &clap::Arg) -> bool {
+        arg.get_long() == Some(self.long.as_str())
+            || (self.short.is_some() && arg.get_short() == self.short)
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(short) = self.short {
+            write!(f, "-{short}/")?;
+        }
+        write!(f, "--{}", self.long)
+    }
+}
+
+/// The global flags added by `cli`. Each flag can be renamed or disabled with `None`.
+///
+/// By default these are `-c/--config`, `--mode`, `-r/--root` and `-v/--verbose`.
+/// `--verbose` is global, so `cli` fails if a command defines an argument with the same
+/// name. Rename the flag or disable it in that case.
+///
+/// uhuh does not install a tracing subscriber. The app sets up its own, using the
+/// level passed to [`on_level`](CliFlags::on_level) or registered as an extension.
+#[derive(Clone)]
+pub struct CliFlags {
+    config: Option<Flag>,
+    mode: Option<Flag>,
+    root: Option<Flag>,
+    verbose: Option<Flag>,
+    level: LevelFilter,
+    on_level: Option<Arc<dyn Fn(LevelFilter) + Send + Sync>>,
+}
+
+impl Default for CliFlags {
+    fn default() -> Self {
+        CliFlags {
+            config: Some(Flag::long("config").short('c')),
+            mode: Some(Flag::long("mode")),
+            root: Some(Flag::long("root").short('r')),
+            verbose: Some(Flag::long("verbose").short('v')),
+            level: LevelFilter::INFO,
+            on_level: None,
+        }
+    }
+}
+
+impl fmt::Debug for CliFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CliFlags")
+            .field("config", &self.config)
+            .field("mode", &self.mode)
+            .field("root", &self.root)
+            .field("verbose", &self.verbose)
+            .field("level", &self.level)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CliFlags {
+    /// No global flags
+    pub fn none() -> CliFlags {
+        CliFlags {
+            config: None,
+            mode: None,
+            root: None,
+            verbose: None,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(mut self, flag: impl Into<Option<Flag>>) -> Self {
+        self.config = flag.into();
+        self
+    }
+
+    pub fn mode(mut self, flag: impl Into<Option<Flag>>) -> Self {
+        self.mode = flag.into();
+        self
+    }
+
+    pub fn root(mut self, flag: impl Into<Option<Flag>>) -> Self {
+        self.root = flag.into();
+        self
+    }
+
+    /// Each occurrence raises the level by one, from the base level up to `TRACE`
+    pub fn verbose(mut self, flag: impl Into<Option<Flag>>) -> Self {
+        self.verbose = flag.into();
+        self
+    }
+
+    /// The level without `--verbose`. Defaults to `INFO`.
+    pub fn level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Called with the selected level before the app is built, to set up a subscriber.
+    /// The level is also registered as an extension. Without a subscriber that uses
+    /// the level, `--verbose` has no effect.
+    pub fn on_level<T>(mut self, func: T) -> Self
+    where
+        T: Fn(LevelFilter) + Send + Sync + 'static,
+    {
+        self.on_level = Some(Arc::new(func));
+        self
+    }
+
+    pub(super) fn apply(&self, mut app: clap::Command) -> clap::Command {
+        if let Some(flag) = &self.config {
+            app = app.arg(flag.arg("config"));
+        }
+
+        if let Some(flag) = &self.mode {
+            app = app.arg(flag.arg("mode"));
+        }
+
+        if let Some(flag) = &self.root {
+            app = app.arg(flag.arg("root"));
+        }
+
+        if let Some(flag) = &self.verbose {
+            app = app.arg(
+                flag.arg("verbose")
+                    .action(clap::ArgAction::Count)
+                    .global(true)
+                    .help("Increase the log level, once for each occurrence"),
+            );
+        }
+
+        app
+    }
+
+    /// Fails if a command defines an argument that clashes with the global verbose flag.
+    pub(super) fn check(&self, app: &clap::Command) -> Result<(), Error> {
+        let Some(flag) = &self.verbose else {
+            return Ok(());
+        };
+
+        check_global(flag, "verbose", app, app.get_name())
+    }
+
+    /// The level selected on the command line, if the verbose flag is enabled.
+    /// Calls the `on_level` hook.
+    pub(super) fn apply_level(&self, matches: &clap::ArgMatches) -> Option<LevelFilter> {
+        self.verbose.as_ref()?;
+
+        let levels = [
+            LevelFilter::OFF,
+            LevelFilter::ERROR,
+            LevelFilter::WARN,
+            LevelFilter::INFO,
+            LevelFilter::DEBUG,
+            LevelFilter::TRACE,
+        ];
+
+        let base = levels
+            .iter()
+            .position(|level| *level == self.level)
+            .unwrap_or(3);
+        let count = matches.get_count("verbose") as usize;
+        let level = levels[(base + count).min(levels.len() - 1)];
+
+        if let Some(on_level) = &self.on_level {
+            on_level(level);
+        }
+
+        Some(level)
+    }
+}
+
+fn check_global(flag: &Flag, id: &str, cmd: &clap::Command, path: &str) -> Result<(), Error> {
+    if let Some(arg) = cmd
+        .get_arguments()
+        .find(|arg| arg.get_id() != id && flag.clashes(arg))
+    {
+        return Err(Error::new(format!(
+            "the global flag {flag} clashes with the argument '{}' of the command '{path}'",
+            arg.get_id()
+        )));
+    }
+
+    for sub in cmd.get_subcommands() {
+        check_global(flag, id, sub, &format!("{path} {}", sub.get_name()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbose_clashes_are_reported() {
+        let flags = CliFlags::default();
+        let app = flags.apply(clap::Command::new("app")).subcommand(
+            clap::Command::new("db")
+                .subcommand(clap::Command::new("seed").arg(clap::Arg::new("version").short('v'))),
+        );
+
+        let err = flags.check(&app).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the global flag -v/--verbose clashes with the argument 'version' of the command 'app db seed'"
+        );
+
+        let flags = flags.verbose(Flag::long("verbose"));
+        let app = flags
+            .apply(clap::Command::new("app"))
+            .subcommand(clap::Command::new("seed").arg(clap::Arg::new("version").short('v')));
+        assert!(flags.check(&app).is_ok());
+    }
+}
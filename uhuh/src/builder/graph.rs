@@ -0,0 +1,515 @@
+use std::any::TypeId;
+
+use vaerdi::hashbrown::HashMap;
+
+use crate::{
+    context::Context,
+    module::{
+        box_concurrent_module, box_module, box_named_module, ConcurrentModule, DynamicModule,
+    },
+    report::{ModuleInfo, ModulePhase},
+    Error, Module,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Active,
+    Done,
+}
+
+struct Node<C> {
+    type_name: &'static str,
+    section: String,
+    added_by: Option<String>,
+    module: Option<Box<dyn DynamicModule<C>>>,
+    state: State,
+    /// Modules that are built before this one
+    deps: Vec<usize>,
+}
+
+/// Modules discovered during setup and the dependencies between them.
+///
+/// A module depends on the modules it declares with `depends_on` and on the modules
+/// it adds with `add_module`. The modules are sorted by depth, so independent modules
+/// are next to each other, and in the order their setup completed within a depth.
+/// That order is used for build, init and finish.
+pub(crate) struct ModuleGraph<C> {
+    nodes: Vec<Node<C>>,
+    index: HashMap<(TypeId, Option<String>), usize>,
+    stack: Vec<usize>,
+    order: Vec<usize>,
+}
+
+impl<C> Node<C> {
+    fn info(&self) -> ModuleInfo {
+        ModuleInfo {
+            type_name: self.type_name,
+            section: self.section.clone(),
+            added_by: self.added_by.clone(),
+            skipped: false,
+            disabled: false,
+            disabled_by: None,
+            phases: if self.state == State::Done {
+                vec![ModulePhase::Setup]
+            } else {
+                Vec::default()
+            },
+        }
+    }
+}
+
+impl<C> Default for ModuleGraph<C> {
+    fn default() -> Self {
+        ModuleGraph {
+            nodes: Vec::default(),
+            index: HashMap::default(),
+            stack: Vec::default(),
+            order: Vec::default(),
+        }
+    }
+}
+
+impl<C: Context> ModuleGraph<C> {
+    pub fn insert<T: Module<C> + 'static>(&mut self) -> usize {
+        let key = (TypeId::of::<T>(), None);
+        if let Some(idx) = self.index.get(&key) {
+            return *idx;
+        }
+
+        self.push(key, box_module::<T, C>())
+    }
+
+    /// Adds `T` as a module that can be built concurrently. A module that was already
+    /// added as a plain module is upgraded, unless it is being set up right now.
+    pub fn insert_concurrent<T: ConcurrentModule<C> + 'static>(&mut self) -> usize {
+        let idx = self.insert::<T>();
+        if let Some(module) = &mut self.nodes[idx].module {
+            *module = box_concurrent_module::<T, C>();
+        }
+        idx
+    }
+
+    /// Adds a named instance of `T`. Fails if another module already uses `name` as its section.
+    pub fn insert_named<T: Module<C> + 'static>(&mut self, name: &str) -> Result<usize, Error> {
+        let key = (TypeId::of::<T>(), Some(name.to_string()));
+        if let Some(idx) = self.index.get(&key) {
+            return Ok(*idx);
+        }
+
+        if self.nodes.iter().any(|node| node.section == name) {
+            return Err(Error::new(format!(
+                "module section '{name}' is already in use"
+            )));
+        }
+
+        Ok(self.push(key, box_named_module::<T, C>(name)))
+    }
+
+    /// Adds `T` from the setup of the current module. If `T` was not registered yet,
+    /// the current module depends on it, so `T` is built first.
+    pub fn insert_child<T: Module<C> + 'static>(&mut self) -> usize {
+        let len = self.nodes.len();
+        let idx = self.insert::<T>();
+        self.link_child(idx, len)
+    }
+
+    pub fn insert_named_child<T: Module<C> + 'static>(
+        &mut self,
+        name: &str,
+    ) -> Result<usize, Error> {
+        let len = self.nodes.len();
+        let idx = self.insert_named::<T>(name)?;
+        Ok(self.link_child(idx, len))
+    }
+
+    /// Only modules added by this call become children. Adding a module that is already
+    /// registered, like the parent adding it, does not change the order.
+    fn link_child(&mut self, idx: usize, len: usize) -> usize {
+        if idx >= len {
+            if let Some(parent) = self.stack.last() {
+                self.nodes[*parent].deps.push(idx);
+            }
+        }
+        idx
+    }
+
+    fn push(&mut self, key: (TypeId, Option<String>), module: Box<dyn DynamicModule<C>>) -> usize {
+        let idx = self.nodes.len();
+        let added_by = self
+            .stack
+            .last()
+            .map(|parent| self.nodes[*parent].section.clone());
+        self.nodes.push(Node {
+            type_name: module.type_name(),
+            section: module.config_section().to_string(),
+            added_by,
+            module: Some(module),
+            state: State::Pending,
+            deps: Vec::default(),
+        });
+        self.index.insert(key, idx);
+
+        idx
+    }
+}
+
+impl<C> ModuleGraph<C> {
+    pub fn next_pending(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.state == State::Pending)
+    }
+
+    /// Checks that depending on `dependency` from the module currently being set up
+    /// does not introduce a cycle. Returns `true` if the dependency still needs to be set up.
+    pub fn check_dependency(&self, dependency: usize) -> Result<bool, Error> {
+        if self.nodes[dependency].state == State::Active {
+            let start = self
+                .stack
+                .iter()
+                .position(|idx| *idx == dependency)
+                .unwrap_or(self.stack.len());
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(dependency);
+            return Err(self.cycle_error(&cycle));
+        }
+
+        Ok(self.nodes[dependency].state == State::Pending)
+    }
+
+    /// The modules registered so far, in the order they were added
+    pub fn infos(&self) -> Vec<ModuleInfo> {
+        self.nodes.iter().map(|node| node.info()).collect()
+    }
+
+    /// Records that the module currently being set up depends on `dependency`.
+    pub fn add_dependency(&mut self, dependency: usize) {
+        if let Some(current) = self.stack.last() {
+            let node = &mut self.nodes[*current];
+            if !node.deps.contains(&dependency) {
+                node.deps.push(dependency);
+            }
+        }
+    }
+
+    /// Takes the module out of the graph while it is being set up.
+    pub fn begin(&mut self, idx: usize) -> Option<Box<dyn DynamicModule<C>>> {
+        let node = &mut self.nodes[idx];
+        if node.state != State::Pending {
+            return None;
+        }
+
+        node.state = State::Active;
+        self.stack.push(idx);
+        node.module.take()
+    }
+
+    pub fn complete(&mut self, idx: usize, module: Box<dyn DynamicModule<C>>) {
+        let node = &mut self.nodes[idx];
+        node.module = Some(module);
+        node.state = State::Done;
+        self.stack.retain(|i| *i != idx);
+        self.order.push(idx);
+    }
+
+    /// Consumes the graph, returning the modules in dependency order.
+    /// Fails if a module added with `add_module` depends on the module that added it.
+    pub fn into_sorted(self) -> Result<Vec<SortedModule<C>>, Error> {
+        let deps = self
+            .nodes
+            .iter()
+            .map(|node| node.deps.clone())
+            .collect::<Vec<_>>();
+
+        let (mut order, depths) = sort_dependencies(&deps, self.order.iter().copied())
+            .map_err(|cycle| self.cycle_error(&cycle))?;
+        order.sort_by_key(|idx| depths[*idx]);
+
+        let mut position = vec![usize::MAX; self.nodes.len()];
+        for (pos, idx) in order.iter().enumerate() {
+            position[*idx] = pos;
+        }
+
+        let mut modules = self
+            .nodes
+            .into_iter()
+            .zip(depths)
+            .zip(deps)
+            .map(|((node, depth), deps)| {
+                let info = node.info();
+                node.module.map(|module| SortedModule {
+                    depth,
+                    deps: deps.iter().map(|dep| position[*dep]).collect(),
+                    module,
+                    info,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(order
+            .into_iter()
+            .filter_map(|idx| modules[idx].take())
+            .collect())
+    }
+
+    fn cycle_error(&self, cycle: &[usize]) -> Error {
+        let cycle = cycle
+            .iter()
+            .map(|idx| self.nodes[*idx].section.as_str())
+            .collect::<Vec<_>>();
+
+        Error::new(format!("module dependency cycle: {}", cycle.join(" -> ")))
+    }
+}
+
+/// A module in build order
+pub(crate) struct SortedModule<C> {
+    /// Modules with the same depth do not depend on each other
+    pub depth: usize,
+    /// The positions of the module's dependencies in the sorted list
+    pub deps: Vec<usize>,
+    pub module: Box<dyn DynamicModule<C>>,
+    pub info: ModuleInfo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    New,
+    Visiting,
+    Done,
+}
+
+struct Sorter<'a> {
+    deps: &'a [Vec<usize>],
+    marks: Vec<Mark>,
+    depths: Vec<usize>,
+    stack: Vec<usize>,
+    order: Vec<usize>,
+}
+
+impl<'a> Sorter<'a> {
+    fn visit(&mut self, idx: usize) -> Result<(), Vec<usize>> {
+        match self.marks[idx] {
+            Mark::Done => return Ok(()),
+            Mark::Visiting => {
+                let start = self
+                    .stack
+                    .iter()
+                    .position(|i| *i == idx)
+                    .unwrap_or_default();
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(idx);
+                return Err(cycle);
+            }
+            Mark::New => {}
+        }
+
+        self.marks[idx] = Mark::Visiting;
+        self.stack.push(idx);
+
+        let deps = self.deps;
+        let mut depth = 0;
+        for dep in &deps[idx] {
+            self.visit(*dep)?;
+            depth = depth.max(self.depths[*dep] + 1);
+        }
+
+        self.stack.pop();
+        self.marks[idx] = Mark::Done;
+        self.depths[idx] = depth;
+        self.order.push(idx);
+
+        Ok(())
+    }
+}
+
+/// Sorts a dependency graph where `deps[idx]` are the nodes `idx` depends on.
+///
+/// Nodes are visited in the order of `roots`, and each node is placed after its dependencies.
+/// Also returns the depth of each node, the length of the longest dependency chain below it.
+/// Fails with the nodes on the first cycle found, starting and ending with the same node.
+pub(crate) fn sort_dependencies(
+    deps: &[Vec<usize>],
+    roots: impl IntoIterator<Item = usize>,
+) -> Result<(Vec<usize>, Vec<usize>), Vec<usize>> {
+    let mut sorter = Sorter {
+        deps,
+        marks: vec![Mark::New; deps.len()],
+        depths: vec![0; deps.len()],
+        stack: Vec::default(),
+        order: Vec::with_capacity(deps.len()),
+    };
+
+    for idx in roots {
+        sorter.visit(idx)?;
+    }
+
+    Ok((sorter.order, sorter.depths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::BuildCtx;
+
+    macro_rules! module {
+        ($name:ident, $section:literal) => {
+            struct $name;
+
+            impl Module<()> for $name {
+                const CONFIG_SECTION: &'static str = $section;
+                type Config = ();
+
+                fn default_config() -> Option<()> {
+                    None
+                }
+
+                async fn build(_ctx: BuildCtx<'_, ()>, _config: ()) -> Result<(), Error> {
+                    Ok(())
+                }
+            }
+        };
+    }
+
+    module!(A, "a");
+    module!(B, "b");
+    module!(D, "d");
+
+    /// Sets up `idx`, running `f` as its setup
+    fn setup(graph: &mut ModuleGraph<()>, idx: usize, f: impl FnOnce(&mut ModuleGraph<()>)) {
+        let module = graph.begin(idx).unwrap();
+        f(graph);
+        graph.complete(idx, module);
+    }
+
+    /// Mirrors `SetupCtx::depends_on`
+    fn depends_on(graph: &mut ModuleGraph<()>, idx: usize) -> Result<(), Error> {
+        if graph.check_dependency(idx)? {
+            setup(graph, idx, |_| {});
+        }
+        graph.add_dependency(idx);
+        Ok(())
+    }
+
+    fn sorted(graph: ModuleGraph<()>) -> Vec<(usize, String)> {
+        graph
+            .into_sorted()
+            .unwrap()
+            .into_iter()
+            .map(|sorted| (sorted.depth, sorted.info.section))
+            .collect()
+    }
+
+    fn setup_pending(graph: &mut ModuleGraph<()>) {
+        while let Some(idx) = graph.next_pending() {
+            setup(graph, idx, |_| {});
+        }
+    }
+
+    #[test]
+    fn dependencies_are_sorted_first() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        graph.insert::<D>();
+        setup(&mut graph, a, |graph| {
+            let b = graph.insert::<B>();
+            depends_on(graph, b).unwrap();
+        });
+        setup_pending(&mut graph);
+
+        assert_eq!(
+            sorted(graph),
+            vec![(0, "b".into()), (0, "d".into()), (1, "a".into())]
+        );
+    }
+
+    #[test]
+    fn added_modules_are_sorted_before_their_parent() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        setup(&mut graph, a, |graph| {
+            graph.insert_child::<B>();
+        });
+        graph.insert::<D>();
+        setup_pending(&mut graph);
+
+        assert_eq!(
+            sorted(graph),
+            vec![(0, "b".into()), (0, "d".into()), (1, "a".into())]
+        );
+    }
+
+    #[test]
+    fn adding_a_registered_module_does_not_add_a_dependency() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        let b = graph.insert::<B>();
+        setup(&mut graph, a, |_| {});
+        setup(&mut graph, b, |graph| {
+            graph.insert_child::<A>();
+        });
+
+        assert_eq!(sorted(graph), vec![(0, "a".into()), (0, "b".into())]);
+    }
+
+    #[test]
+    fn add_module_and_depends_on() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        setup(&mut graph, a, |graph| {
+            graph.insert_child::<B>();
+            let d = graph.insert::<D>();
+            depends_on(graph, d).unwrap();
+        });
+        setup_pending(&mut graph);
+
+        assert_eq!(
+            sorted(graph),
+            vec![(0, "d".into()), (0, "b".into()), (1, "a".into())]
+        );
+    }
+
+    #[test]
+    fn depends_on_cycle_fails_during_setup() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        let module = graph.begin(a).unwrap();
+        let b = graph.insert::<B>();
+        assert!(graph.check_dependency(b).unwrap());
+        let module_b = graph.begin(b).unwrap();
+
+        let err = graph.check_dependency(a).unwrap_err();
+        assert_eq!(err.to_string(), "module dependency cycle: a -> b -> a");
+
+        graph.complete(b, module_b);
+        graph.complete(a, module);
+    }
+
+    #[test]
+    fn child_depending_on_parent_is_a_cycle() {
+        let mut graph = ModuleGraph::default();
+        let a = graph.insert::<A>();
+        let mut b = 0;
+        setup(&mut graph, a, |graph| {
+            b = graph.insert_child::<B>();
+        });
+        setup(&mut graph, b, |graph| {
+            depends_on(graph, a).unwrap();
+        });
+
+        let err = graph.into_sorted().err().unwrap();
+        assert_eq!(err.to_string(), "module dependency cycle: a -> b -> a");
+    }
+
+    #[test]
+    fn sort_dependencies_reports_depths() {
+        let deps = vec![vec![1, 2], vec![2], vec![]];
+        let (order, depths) = sort_dependencies(&deps, 0..deps.len()).unwrap();
+        assert_eq!(order, vec![2, 1, 0]);
+        assert_eq!(depths, vec![2, 1, 0]);
+
+        let deps = vec![vec![1], vec![0]];
+        assert_eq!(sort_dependencies(&deps, 0..deps.len()), Err(vec![0, 1, 0]));
+    }
+}
@@ -0,0 +1,243 @@
+use std::path::{Path, PathBuf};
+
+use extensions::concurrent::Extensions;
+use johnfig::Config;
+
+use crate::{
+    context::Context,
+    initializer::Initializer,
+    module::{box_module, box_named_module, DynamicModule},
+    named::{Lifecycle, NamedExtensions},
+    overrides::Overrides,
+    plugin::PluginsList,
+    providers::Providers,
+    uhuh::{SharedConfig, Uhuh},
+    ConfigError, Error, Mode, Module,
+};
+
+use super::{build::Registry, config, BuildCtx, InitCtx};
+
+/// Runs the phases of single modules against an in-memory context, without
+/// config files, an executor or the rest of the app.
+///
+/// Values registered before a phase stand in for the modules it depends on.
+pub struct ModuleHarness<C> {
+    ctx: C,
+    config: Config,
+    mode: Mode,
+    root: PathBuf,
+    initializers: Vec<Box<dyn Initializer<C>>>,
+    extensions: Extensions,
+    providers: Providers,
+    overrides: Overrides,
+    named: NamedExtensions,
+    plugins: PluginsList<C>,
+    lifecycle: Lifecycle,
+}
+
+impl<C: Context> ModuleHarness<C> {
+    pub fn new(ctx: C, root: impl Into<PathBuf>) -> ModuleHarness<C> {
+        ModuleHarness {
+            ctx,
+            config: Config::default(),
+            mode: Mode::Development,
+            root: root.into(),
+            initializers: Vec::default(),
+            extensions: Extensions::default(),
+            providers: Providers::default(),
+            overrides: Overrides::default(),
+            named: NamedExtensions::default(),
+            plugins: PluginsList::default(),
+            lifecycle: Lifecycle::default(),
+        }
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set a config section, like `db` or `db.replica`
+    pub fn set_config<T: serde::Serialize>(
+        &mut self,
+        section: &str,
+        value: T,
+    ) -> Result<&mut Self, Error> {
+        config::set_section(&mut self.config, section, vaerdi::ser::to_value(value)?)?;
+        Ok(self)
+    }
+
+    pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.providers.insert::<T>("harness");
+        self.extensions.insert(value);
+        self
+    }
+
+    /// Register a value the module can not replace, see `Builder::override_ext`
+    pub fn override_ext<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.overrides
+            .pin(value, &mut self.extensions, &mut self.providers);
+        self
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get::<T>()
+    }
+
+    /// Get a value registered by the named module instance `name`
+    pub fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.named.get::<T>(name)
+    }
+
+    /// Get a value registered with `register_lifecycle`
+    pub fn get_lifecycle<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.lifecycle.get::<T>(None)
+    }
+
+    /// Get a value registered with `register_lifecycle` by the named module instance `name`
+    pub fn get_named_lifecycle<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.lifecycle.get::<T>(Some(name))
+    }
+
+    pub fn ctx(&self) -> &C {
+        &self.ctx
+    }
+
+    pub fn ctx_mut(&mut self) -> &mut C {
+        &mut self.ctx
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Run `T::build` with the config section of `T`, or its default config.
+    /// `T::setup` is not run, so dependencies must be registered up front.
+    pub async fn build<T: Module<C> + 'static>(&mut self) -> Result<(), Error> {
+        self.build_module(&*box_module::<T, C>()).await
+    }
+
+    /// Like [`build`](Self::build), for a named instance reading the section `name`
+    pub async fn build_named<T: Module<C> + 'static>(&mut self, name: &str) -> Result<(), Error> {
+        self.build_module(&*box_named_module::<T, C>(name)).await
+    }
+
+    /// Run `T::init`, followed by the initializers added while building
+    pub async fn init<T: Module<C> + 'static>(&mut self) -> Result<(), Error> {
+        self.init_module(&*box_module::<T, C>()).await
+    }
+
+    pub async fn init_named<T: Module<C> + 'static>(&mut self, name: &str) -> Result<(), Error> {
+        self.init_module(&*box_named_module::<T, C>(name)).await
+    }
+
+    /// Build and init `T`
+    pub async fn run<T: Module<C> + 'static>(&mut self) -> Result<(), Error> {
+        self.build::<T>().await?;
+        self.init::<T>().await
+    }
+
+    /// Build the context output from the registered values and run `T::finish` on it
+    pub async fn finish<T: Module<C> + 'static>(self) -> Result<C::Output, Error> {
+        let module = box_module::<T, C>();
+
+        let app = Uhuh {
+            extensions: self.extensions,
+            config: SharedConfig::new(self.config),
+            mode: self.mode,
+            root: self.root,
+            name: "harness".to_string(),
+            providers: self.providers,
+            named: self.named,
+            overrides: self.overrides,
+            states: Default::default(),
+        };
+
+        let mut output = self.ctx.build(app).await?;
+        module.finish(&mut output).await?;
+
+        Ok(output)
+    }
+
+    async fn build_module(&mut self, module: &dyn DynamicModule<C>) -> Result<(), Error> {
+        let section = module.config_section();
+
+        let cfg = match config::get_section(&self.config, section) {
+            Some(cfg) => cfg,
+            None => module
+                .default_config()
+                .ok_or_else(|| ConfigError::missing(section))?,
+        };
+
+        module.validate_config(&cfg)?;
+
+        module
+            .build(
+                BuildCtx {
+                    ctx: &mut self.ctx,
+                    plugins: &mut self.plugins,
+                    registry: Registry {
+                        module: section,
+                        instance: module.instance(),
+                        initializers: &mut self.initializers,
+                        extensions: &mut self.extensions,
+                        providers: &mut self.providers,
+                        overrides: &self.overrides,
+                        named: &mut self.named,
+                        shared: None,
+                        mode: &self.mode,
+                        root: &self.root,
+                        lifecycle: &mut self.lifecycle,
+                        executor: None,
+                    },
+                },
+                cfg,
+            )
+            .await
+    }
+
+    async fn init_module(&mut self, module: &dyn DynamicModule<C>) -> Result<(), Error> {
+        module
+            .init(InitCtx {
+                module: module.config_section(),
+                instance: module.instance(),
+                ctx: &mut self.ctx,
+                ext: &mut self.extensions,
+                providers: &mut self.providers,
+                overrides: &self.overrides,
+                named: &mut self.named,
+                config: &self.config,
+                root: &self.root,
+                lifecycle: &mut self.lifecycle,
+            })
+            .await?;
+
+        for initializer in core::mem::take(&mut self.initializers) {
+            initializer
+                .call(InitCtx {
+                    module: initializer.name(),
+                    instance: None,
+                    ctx: &mut self.ctx,
+                    ext: &mut self.extensions,
+                    providers: &mut self.providers,
+                    overrides: &self.overrides,
+                    named: &mut self.named,
+                    config: &self.config,
+                    root: &self.root,
+                    lifecycle: &mut self.lifecycle,
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+}
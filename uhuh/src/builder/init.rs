@@ -1,76 +1,336 @@
 use crate::{
-    context::Context, initializer::Initializer, module::DynamicModule, uhuh::Uhuh, Error, Mode,
+    context::Context,
+    initializer::{InitOrder, Initializer},
+    module::DynamicModule,
+    named::{Lifecycle, NamedExtensions},
+    overrides::Overrides,
+    providers::{MissingExtension, Providers},
+    report::{self, ModulePhase, ModuleStates, StartupReport},
+    uhuh::{SharedConfig, Uhuh},
+    Error, Mode,
 };
+use bobestyrer::AnyExecutor;
 use extensions::concurrent::Extensions;
 use johnfig::Config;
 use std::{
     future::Future,
     path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
 };
+use tracing::warn;
 
-use super::Phase;
+use super::{config::ConfigSources, shutdown::abort, ConfigWatcher, Phase, Shutdown};
 
 pub struct Init<C> {
     pub(super) ctx: C,
     pub(super) initializers: Vec<Box<dyn Initializer<C>>>,
     pub(super) extensions: Extensions,
+    pub(super) providers: Providers,
+    pub(super) overrides: Overrides,
+    pub(super) named: NamedExtensions,
     pub(super) config: Config,
     pub(super) name: String,
     pub(super) mode: Mode,
     pub(super) root: PathBuf,
     pub(super) modules: Vec<Box<dyn DynamicModule<C>>>,
+    /// Whether each module was built. Modules skipped for a missing config are not shut down.
+    pub(super) built: Vec<bool>,
+    pub(super) states: ModuleStates,
+    pub(super) lifecycle: Lifecycle,
+    pub(super) executor: AnyExecutor,
+    pub(super) shutdown_timeout: Duration,
+    pub(super) config_sources: Option<ConfigSources>,
+    pub(super) report: Option<StartupReport>,
 }
 
 impl<C: Context> Phase for Init<C> {
-    type Next = C::Output;
+    type Next = (C::Output, Shutdown<C>);
     fn next(mut self) -> impl Future<Output = Result<Self::Next, Error>> {
         async move {
-            for module in &self.modules {
-                module
-                    .init(InitCtx {
-                        ctx: &mut self.ctx,
-                        ext: &mut self.extensions,
-                        config: &self.config,
-                        root: &self.root,
-                    })
-                    .await?;
+            if let Err(err) = self.init_modules().await {
+                return Err(self.teardown(err).await);
             }
 
-            for initializer in self.initializers.into_iter() {
-                initializer
-                    .call(InitCtx {
-                        ctx: &mut self.ctx,
-                        ext: &mut self.extensions,
-                        config: &self.config,
-                        root: &self.root,
-                    })
-                    .await?;
+            let mode = self.mode.clone();
+            let root = self.root.clone();
+            let config = SharedConfig::new(self.config);
+
+            if let Some(report) = &self.report {
+                self.extensions.insert(report.clone());
             }
 
             let app = Uhuh {
                 extensions: self.extensions,
-                config: self.config,
+                config: config.clone(),
                 mode: self.mode,
                 root: self.root,
                 name: self.name,
+                providers: self.providers,
+                named: self.named,
+                overrides: self.overrides,
+                states: self.states.clone(),
+            };
+
+            let ret = match self.ctx.build(app).await {
+                Ok(mut app) => finish(&self.modules, &self.states, self.report.as_ref(), &mut app)
+                    .await
+                    .map(|_| app),
+                Err(err) => Err(err),
             };
 
-            let mut app = self.ctx.build(app).await?;
+            let modules = self
+                .modules
+                .into_iter()
+                .zip(self.built)
+                .filter_map(|(module, built)| built.then_some(module))
+                .collect::<Vec<_>>();
 
-            for module in self.modules {
-                module.finish(&mut app).await?;
-            }
+            let app = match ret {
+                Ok(app) => app,
+                Err(err) => {
+                    return Err(abort(
+                        err,
+                        modules.iter(),
+                        &self.lifecycle,
+                        &mode,
+                        &root,
+                        self.shutdown_timeout,
+                    )
+                    .await);
+                }
+            };
+
+            let modules = Rc::new(modules);
+            let lifecycle = Rc::new(self.lifecycle);
+
+            let watcher = self.config_sources.map(|sources| ConfigWatcher {
+                modules: modules.clone(),
+                lifecycle: lifecycle.clone(),
+                sources,
+                current: config,
+                mode: mode.clone(),
+                root: root.clone(),
+                executor: self.executor,
+            });
+
+            let shutdown = Shutdown {
+                modules,
+                lifecycle,
+                mode,
+                root,
+                timeout: self.shutdown_timeout,
+                watcher,
+            };
+
+            Ok((app, shutdown))
+        }
+    }
+}
+
+impl<C: Context> Init<C> {
+    /// The app as it is after build, before any module is initialized,
+    /// with the shutdown of the modules that were built
+    #[cfg(feature = "cli")]
+    pub(super) fn into_app(self) -> (Uhuh, Shutdown<C>) {
+        let modules = self
+            .modules
+            .into_iter()
+            .zip(self.built)
+            .filter_map(|(module, built)| built.then_some(module))
+            .collect::<Vec<_>>();
+
+        let shutdown = Shutdown {
+            modules: Rc::new(modules),
+            lifecycle: Rc::new(self.lifecycle),
+            mode: self.mode.clone(),
+            root: self.root.clone(),
+            timeout: self.shutdown_timeout,
+            watcher: None,
+        };
+
+        let app = Uhuh {
+            extensions: self.extensions,
+            config: SharedConfig::new(self.config),
+            mode: self.mode,
+            root: self.root,
+            name: self.name,
+            providers: self.providers,
+            named: self.named,
+            overrides: self.overrides,
+            states: self.states,
+        };
+
+        (app, shutdown)
+    }
+
+    /// Runs the initializers and the init of each module
+    async fn init_modules(&mut self) -> Result<(), Error> {
+        let initializers = core::mem::take(&mut self.initializers);
+        let slots = self.schedule(&initializers);
+
+        self.run_initializers(&slots[0]).await?;
+
+        for idx in 0..self.modules.len() {
+            self.run_initializers(&slots[1 + 2 * idx]).await?;
+            self.init_module(idx).await?;
+            self.run_initializers(&slots[2 + 2 * idx]).await?;
+        }
+
+        self.run_initializers(&slots[slots.len() - 1]).await
+    }
+
+    /// Shuts down the modules that were built, then returns `err`
+    async fn teardown(&self, err: Error) -> Error {
+        let modules = self
+            .modules
+            .iter()
+            .zip(&self.built)
+            .filter_map(|(module, built)| built.then_some(module));
 
-            Ok(app)
+        abort(
+            err,
+            modules,
+            &self.lifecycle,
+            &self.mode,
+            &self.root,
+            self.shutdown_timeout,
+        )
+        .await
+    }
+
+    /// Sorts initializers into slots: before all modules, before and after each module
+    /// in init order, and after all modules.
+    fn schedule<'a>(
+        &self,
+        initializers: &'a [Box<dyn Initializer<C>>],
+    ) -> Vec<Vec<&'a dyn Initializer<C>>> {
+        let mut slots = vec![Vec::new(); 2 * self.modules.len() + 2];
+        let last = slots.len() - 1;
+
+        for initializer in initializers {
+            let position = |section: &str| {
+                let idx = self
+                    .modules
+                    .iter()
+                    .position(|module| module.config_section() == section);
+
+                if idx.is_none() {
+                    warn!(
+                        initializer = initializer.name(),
+                        "module '{section}' is not loaded, running initializer after all modules"
+                    );
+                }
+
+                idx
+            };
+
+            let slot = match initializer.order() {
+                InitOrder::BeforeModules => 0,
+                InitOrder::AfterModules => last,
+                InitOrder::Before(section) => position(&section).map_or(last, |idx| 1 + 2 * idx),
+                InitOrder::After(section) => position(&section).map_or(last, |idx| 2 + 2 * idx),
+            };
+
+            slots[slot].push(&**initializer);
         }
+
+        slots
+    }
+
+    async fn run_initializers(
+        &mut self,
+        initializers: &[&dyn Initializer<C>],
+    ) -> Result<(), Error> {
+        for initializer in initializers {
+            let init = initializer.call(InitCtx {
+                module: initializer.name(),
+                instance: None,
+                ctx: &mut self.ctx,
+                ext: &mut self.extensions,
+                providers: &mut self.providers,
+                overrides: &self.overrides,
+                named: &mut self.named,
+                config: &self.config,
+                root: &self.root,
+                lifecycle: &mut self.lifecycle,
+            });
+
+            report::phase_async(
+                self.report.as_ref(),
+                initializer.name(),
+                ModulePhase::Init,
+                init,
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn init_module(&mut self, idx: usize) -> Result<(), Error> {
+        let module = &self.modules[idx];
+
+        let init = module.init(InitCtx {
+            module: module.config_section(),
+            instance: module.instance(),
+            ctx: &mut self.ctx,
+            ext: &mut self.extensions,
+            providers: &mut self.providers,
+            overrides: &self.overrides,
+            named: &mut self.named,
+            config: &self.config,
+            root: &self.root,
+            lifecycle: &mut self.lifecycle,
+        });
+
+        report::phase_async(
+            self.report.as_ref(),
+            module.config_section(),
+            ModulePhase::Init,
+            init,
+        )
+        .await?;
+
+        self.states
+            .complete(module.config_section(), ModulePhase::Init);
+
+        Ok(())
     }
 }
 
+async fn finish<C: Context>(
+    modules: &[Box<dyn DynamicModule<C>>],
+    states: &ModuleStates,
+    report: Option<&StartupReport>,
+    app: &mut C::Output,
+) -> Result<(), Error> {
+    for module in modules {
+        report::phase_async(
+            report,
+            module.config_section(),
+            ModulePhase::Finish,
+            module.finish(app),
+        )
+        .await?;
+
+        states.complete(module.config_section(), ModulePhase::Finish);
+    }
+
+    Ok(())
+}
+
 pub struct InitCtx<'a, C> {
-    ctx: &'a mut C,
-    ext: &'a mut Extensions,
-    config: &'a Config,
-    root: &'a Path,
+    pub(super) module: &'a str,
+    pub(super) instance: Option<&'a str>,
+    pub(super) ctx: &'a mut C,
+    pub(super) ext: &'a mut Extensions,
+    pub(super) providers: &'a mut Providers,
+    pub(super) overrides: &'a Overrides,
+    pub(super) named: &'a mut NamedExtensions,
+    pub(super) config: &'a Config,
+    pub(super) root: &'a Path,
+    pub(super) lifecycle: &'a mut Lifecycle,
 }
 
 impl<'a, C> InitCtx<'a, C> {
@@ -78,17 +338,69 @@ impl<'a, C> InitCtx<'a, C> {
         self.root
     }
 
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.instance
+    }
+
+    /// Register a value. Values registered by a named instance are stored under
+    /// the instance name, see [`get_named`](Self::get_named).
     pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
-        self.ext.insert(value);
+        if self.overrides.ignores::<T>(self.module) {
+            return self;
+        }
+
+        match self.instance {
+            Some(name) => self.named.insert(name, value),
+            None => {
+                self.providers.insert::<T>(self.module);
+                self.ext.insert(value);
+            }
+        }
         self
     }
 
+    /// Register a value for the module lifecycle hooks, like a pool that must be closed
+    /// on shutdown. It is available from [`ShutdownCtx::get`](super::ShutdownCtx::get)
+    /// and [`ReconfigureCtx::get`](super::ReconfigureCtx::get).
+    /// Values registered by a named instance are only visible to that instance.
+    pub fn register_lifecycle<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        self.lifecycle.insert(self.instance, value);
+        self
+    }
+
+    /// Get a value. A named instance sees the values it registered itself first.
     pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
-        self.ext.get::<T>()
+        self.instance
+            .and_then(|name| self.named.get::<T>(name))
+            .or_else(|| self.ext.get::<T>())
     }
 
+    /// Get a value registered by the named module instance `name`
+    pub fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.named.get::<T>(name)
+    }
+
+    /// Like [`get`](Self::get), but fails with an error naming the type
+    /// and the module expected to register it.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        self.get::<T>().ok_or_else(|| {
+            MissingExtension::new::<T>()
+                .with_provider(self.providers.get::<T>())
+                .into()
+        })
+    }
+
+    /// Get a value mutably. Values pinned with `override_ext` can not be changed, so this returns `None` for them.
     pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
-        self.ext.get_mut::<T>()
+        if self.overrides.locks::<T>(self.module) {
+            return None;
+        }
+
+        match self.instance {
+            Some(name) if self.named.get::<T>(name).is_some() => self.named.get_mut::<T>(name),
+            _ => self.ext.get_mut::<T>(),
+        }
     }
 
     pub fn config(&self) -> &Config {
@@ -108,3 +420,105 @@ impl<'a, C> core::ops::DerefMut for InitCtx<'a, C> {
         self.ctx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BuildCtx, Builder, InitializerExt, Mode, Module};
+    use bobestyrer::Tokio;
+    use std::sync::Mutex;
+
+    static ORDER: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    fn log(name: &'static str) {
+        ORDER.lock().unwrap().push(name);
+    }
+
+    /// An initializer that logs `name`
+    fn logger(name: &'static str) -> impl Fn(InitCtx<'_, ()>) -> Result<(), Error> {
+        move |_| {
+            log(name);
+            Ok(())
+        }
+    }
+
+    #[derive(Default, serde::Serialize, serde::Deserialize)]
+    struct Empty {}
+
+    struct First;
+
+    impl Module<()> for First {
+        const CONFIG_SECTION: &'static str = "first";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn init(_: InitCtx<'_, ()>) -> Result<(), Error> {
+            log("first");
+            Ok(())
+        }
+    }
+
+    struct Second;
+
+    impl Module<()> for Second {
+        const CONFIG_SECTION: &'static str = "second";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        fn setup(mut ctx: crate::SetupCtx<'_, ()>) -> Result<(), Error> {
+            ctx.depends_on::<First>()?;
+            Ok(())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn init(_: InitCtx<'_, ()>) -> Result<(), Error> {
+            log("second");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn initializers_run_in_their_slots() {
+        Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .module::<Second>()
+            .initializer(logger("after all"))
+            .initializer(logger("unknown").before("missing"))
+            .initializer(logger("after second").after("second"))
+            .initializer(logger("before second").before("second"))
+            .initializer(logger("after first").after("first"))
+            .initializer(logger("before first").before("first"))
+            .initializer(logger("before all").ordered(InitOrder::BeforeModules))
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *ORDER.lock().unwrap(),
+            vec![
+                "before all",
+                "before first",
+                "first",
+                "after first",
+                "before second",
+                "second",
+                "after second",
+                "after all",
+                "unknown",
+            ]
+        );
+    }
+}
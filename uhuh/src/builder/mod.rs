@@ -1,9 +1,24 @@
 mod build;
 mod builder;
 #[cfg(feature = "cli")]
+mod builtin;
+#[cfg(feature = "cli")]
 mod cmd;
 mod config;
+#[cfg(feature = "cli")]
+mod flags;
+mod graph;
+#[cfg(feature = "testing")]
+mod harness;
 mod init;
+mod reload;
 mod setup;
+mod shutdown;
 
-pub use self::{build::*, builder::*, init::*, setup::*};
+pub use self::config::set_section;
+#[cfg(feature = "cli")]
+pub use self::flags::{CliFlags, Flag};
+#[cfg(feature = "testing")]
+pub use self::harness::ModuleHarness;
+pub use self::{build::*, builder::*, init::*, reload::*, setup::*, shutdown::*};
+pub(crate) use self::{config::get_section, graph::sort_dependencies};
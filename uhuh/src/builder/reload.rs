@@ -0,0 +1,340 @@
+use bobestyrer::{AnyExecutor, Executor, JoinHandle};
+use johnfig::Config;
+use std::{
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use tracing::{info, warn};
+
+use crate::{module::DynamicModule, named::Lifecycle, uhuh::SharedConfig, Error, Mode};
+
+use super::config::{get_section, set_section, ConfigSources};
+
+/// Passed to [`Module::reconfigure`](crate::Module::reconfigure) when the module's
+/// config section changes.
+pub struct ReconfigureCtx<'a> {
+    lifecycle: &'a Lifecycle,
+    instance: Option<&'a str>,
+    config: &'a Config,
+    mode: &'a Mode,
+    root: &'a Path,
+}
+
+impl<'a> ReconfigureCtx<'a> {
+    pub fn mode(&self) -> &Mode {
+        self.mode
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root
+    }
+
+    /// The complete, newly loaded config
+    pub fn config(&self) -> &Config {
+        self.config
+    }
+
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.instance
+    }
+
+    /// Get a value the module or instance registered with `register_lifecycle` during build or init.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.lifecycle.get::<T>(self.instance)
+    }
+}
+
+/// Reloads the config from the files it was loaded from, and hands changed
+/// sections to the modules owning them.
+pub struct ConfigWatcher<C> {
+    pub(super) modules: Rc<Vec<Box<dyn DynamicModule<C>>>>,
+    pub(super) lifecycle: Rc<Lifecycle>,
+    pub(super) sources: ConfigSources,
+    /// Shared with the app, so [`Uhuh::config`](crate::Uhuh::config) sees reloads
+    pub(super) current: SharedConfig,
+    pub(super) mode: Mode,
+    pub(super) root: PathBuf,
+    pub(super) executor: AnyExecutor,
+}
+
+impl<C> ConfigWatcher<C> {
+    /// The config files being watched
+    pub fn files(&self) -> &[PathBuf] {
+        self.sources.files()
+    }
+
+    /// Reload the config now. Modules whose section did not change are left alone.
+    ///
+    /// A module that rejects the new config, or fails to apply it, keeps running
+    /// with its old config. Its section keeps the old value in
+    /// [`Uhuh::config`](crate::Uhuh::config), so the change is retried on the next reload.
+    pub async fn reload(&mut self) -> Result<(), Error> {
+        let sources = self.sources.clone();
+        let config = self
+            .executor
+            .spawn_blocking(move || sources.load())
+            .into_future()
+            .await
+            .map_err(Error::new)??;
+
+        let mut kept = Vec::default();
+
+        for module in self.modules.iter() {
+            let section = module.config_section();
+
+            let old = get_section(&self.current.read(), section);
+            let new = get_section(&config, section);
+
+            if section_value(old.as_ref())? == section_value(new.as_ref())? {
+                continue;
+            }
+
+            let Some(old) = old else {
+                warn!(module = ?section, "Config section added for a module that was not built. Restart to enable it");
+                continue;
+            };
+
+            let Some(new) = new else {
+                warn!(module = ?section, "Config section removed. Keeping old config");
+                kept.push((section, old));
+                continue;
+            };
+
+            info!(module = ?section, "Config changed");
+
+            let ctx = ReconfigureCtx {
+                lifecycle: &self.lifecycle,
+                instance: module.instance(),
+                config: &config,
+                mode: &self.mode,
+                root: &self.root,
+            };
+
+            match module.reconfigure(ctx, new).await {
+                Ok(true) => {
+                    info!(module = ?section, "Module reconfigured");
+                }
+                Ok(false) => {
+                    warn!(module = ?section, "Module does not support reconfiguration. Keeping old config");
+                    kept.push((section, old));
+                }
+                Err(err) => {
+                    warn!(module = ?section, error = %err, "Reconfiguration failed. Keeping old config");
+                    kept.push((section, old));
+                }
+            }
+        }
+
+        let mut config = config;
+        for (section, old) in kept {
+            set_section(&mut config, section, old)?;
+        }
+
+        self.current.replace(config);
+
+        Ok(())
+    }
+
+    /// Watch the config files and reload when they change. Runs until the watcher fails.
+    #[cfg(feature = "watch")]
+    pub async fn run(mut self) -> Result<(), Error> {
+        use futures_core::Stream;
+        use notify::Watcher;
+        use std::{future::poll_fn, pin::Pin, time::Duration};
+        use tracing::debug;
+
+        use super::shutdown::sleep;
+
+        let files = self
+            .files()
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect::<Vec<_>>();
+
+        if files.is_empty() {
+            debug!("No config files to watch");
+            return Ok(());
+        }
+
+        let (mut sx, mut rx) = futures_channel::mpsc::channel::<()>(0);
+
+        let watched = files.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+
+                if event.paths.iter().any(|path| watched.contains(path)) {
+                    // A full channel means a reload is already pending
+                    sx.try_send(()).ok();
+                }
+            })
+            .map_err(Error::new)?;
+
+        // Editors often replace files instead of writing to them, so watch the directories
+        let mut dirs = files
+            .iter()
+            .filter_map(|path| path.parent())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in dirs {
+            watcher
+                .watch(dir, notify::RecursiveMode::NonRecursive)
+                .map_err(Error::new)?;
+        }
+
+        debug!(files = ?files, "Watching config files");
+
+        while poll_fn(|cx| Pin::new(&mut rx).poll_next(cx))
+            .await
+            .is_some()
+        {
+            // Let a burst of writes settle before reading the files
+            sleep(Duration::from_millis(200)).await;
+
+            if let Err(err) = self.reload().await {
+                warn!(error = %err, "Could not reload config");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn section_value(value: Option<&vaerdi::Value>) -> Result<serde_json::Value, Error> {
+    match value {
+        Some(value) => serde_json::to_value(value).map_err(Error::new),
+        None => Ok(serde_json::Value::Null),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ConfigError, BuildCtx, Builder, Module, Uhuh};
+    use bobestyrer::Tokio;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default, serde::Serialize, serde::Deserialize)]
+    struct Settings {
+        value: u32,
+    }
+
+    static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+    static REJECTED: AtomicUsize = AtomicUsize::new(0);
+    static UNCHANGED: AtomicUsize = AtomicUsize::new(0);
+
+    macro_rules! module {
+        ($name:ident, $section:literal, $calls:ident, $accept:expr) => {
+            struct $name;
+
+            impl Module<()> for $name {
+                const CONFIG_SECTION: &'static str = $section;
+                type Config = Settings;
+
+                fn default_config() -> Option<Settings> {
+                    Some(Settings { value: 1 })
+                }
+
+                async fn build(_: BuildCtx<'_, ()>, _: Settings) -> Result<(), Error> {
+                    Ok(())
+                }
+
+                async fn reconfigure(_: ReconfigureCtx<'_>, _: Settings) -> Result<bool, Error> {
+                    $calls.fetch_add(1, Ordering::SeqCst);
+                    $accept
+                }
+            }
+        };
+    }
+
+    module!(Accept, "accept", ACCEPTED, Ok(true));
+    module!(Reject, "reject", REJECTED, Ok(false));
+    module!(Unchanged, "unchanged", UNCHANGED, Ok(true));
+
+    struct Failing;
+
+    impl Module<()> for Failing {
+        const CONFIG_SECTION: &'static str = "failing";
+        type Config = Settings;
+
+        fn default_config() -> Option<Settings> {
+            Some(Settings { value: 1 })
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Settings) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn reconfigure(_: ReconfigureCtx<'_>, _: Settings) -> Result<bool, Error> {
+            Err(Error::new("can not reconfigure"))
+        }
+    }
+
+    /// Does not implement `reconfigure`
+    struct NoHook;
+
+    impl Module<()> for NoHook {
+        const CONFIG_SECTION: &'static str = "nohook";
+        type Config = Settings;
+
+        fn default_config() -> Option<Settings> {
+            Some(Settings { value: 1 })
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Settings) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn value(app: &Uhuh, section: &str) -> u32 {
+        let value = get_section(&app.config(), section).unwrap();
+        ConfigError::deserialize::<Settings>(section, &value)
+            .unwrap()
+            .value
+    }
+
+    #[tokio::test]
+    async fn only_accepted_sections_are_stored() {
+        let (app, mut shutdown) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .config_env_prefix("UHUH_RELOAD_TEST")
+            .watch_config(true)
+            .module::<Accept>()
+            .module::<Reject>()
+            .module::<Unchanged>()
+            .module::<Failing>()
+            .module::<NoHook>()
+            .build()
+            .await
+            .unwrap();
+
+        let mut watcher = shutdown.take_config_watcher().unwrap();
+
+        for section in ["ACCEPT", "REJECT", "FAILING", "NOHOOK"] {
+            std::env::set_var(format!("UHUH_RELOAD_TEST__{section}__VALUE"), "2");
+        }
+
+        watcher.reload().await.unwrap();
+
+        assert_eq!(ACCEPTED.load(Ordering::SeqCst), 1);
+        assert_eq!(REJECTED.load(Ordering::SeqCst), 1);
+        assert_eq!(UNCHANGED.load(Ordering::SeqCst), 0);
+
+        assert_eq!(value(&app, "accept"), 2);
+        assert_eq!(value(&app, "reject"), 1);
+        assert_eq!(value(&app, "failing"), 1);
+        assert_eq!(value(&app, "nohook"), 1);
+        assert_eq!(value(&app, "unchanged"), 1);
+
+        // Kept sections still differ from the files, so they are retried
+        watcher.reload().await.unwrap();
+
+        assert_eq!(ACCEPTED.load(Ordering::SeqCst), 1);
+        assert_eq!(REJECTED.load(Ordering::SeqCst), 2);
+    }
+}
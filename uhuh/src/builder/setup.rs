@@ -2,40 +2,67 @@ use crate::{
     configure::Configure,
     context::Context,
     initializer::Initializer,
-    module::{box_module, DynamicModule},
+    named::NamedExtensions,
+    overrides::Overrides,
     plugin::PluginsList,
-    Error, Mode, Module, Plugin,
+    providers::Providers,
+    report::{self, ModuleInfo, ModulePhase, ModuleStates, StartupReport},
+    root::RootResolver,
+    secrets::SecretProvider,
+    ConcurrentModule, ConfiguredPlugin, Error, Mode, Module, Plugin,
 };
 use bobestyrer::AnyExecutor;
 use extensions::concurrent::Extensions;
 use johnfig::Config;
-use std::{any::TypeId, collections::VecDeque, future::Future, path::PathBuf};
+use std::{future::Future, path::PathBuf, sync::Arc, time::Duration};
 use tracing::debug;
-use vaerdi::hashbrown::HashSet;
 
-use super::{config::ConfigBuilder, Build, Builder, Phase};
+use super::{
+    config::{self, ConfigBuilder},
+    graph::ModuleGraph,
+    Build, Builder, Phase, Shutdown,
+};
 
 #[cfg(feature = "cli")]
-use super::cmd::*;
+use super::{cmd::*, CliFlags};
 
 impl<C> Builder<Setup<C>>
 where
     C: Context,
 {
     pub fn new<E: Into<AnyExecutor>>(ctx: C, name: &str, mode: Mode, executor: E) -> Self {
+        let mut modes = vec![Mode::Development, Mode::Production];
+        if !modes.contains(&mode) {
+            modes.push(mode.clone());
+        }
+
         Self {
             phase: Setup {
                 ctx,
-                modules: Vec::default(),
+                graph: ModuleGraph::default(),
                 initializers: Vec::default(),
+                modes,
                 mode,
                 name: name.to_string(),
                 skip_on_missing_config: false,
                 root: None,
+                root_resolver: RootResolver::default(),
                 config_builder: ConfigBuilder::default(),
-                module_map: Default::default(),
                 executor: executor.into(),
                 plugins: Default::default(),
+                extensions: Extensions::default(),
+                providers: Providers::default(),
+                overrides: Overrides::default(),
+                named: NamedExtensions::default(),
+                defaults: Config::default(),
+                shutdown_timeout: Duration::from_secs(30),
+                watch_config: false,
+                concurrent_build: false,
+                report: None,
+                #[cfg(feature = "cli")]
+                cmds: Vec::default(),
+                #[cfg(feature = "cli")]
+                cli_flags: CliFlags::default(),
             },
         }
     }
@@ -50,6 +77,33 @@ where
         self
     }
 
+    /// How the root is found if it is not set with [`root`](Self::root) or `--root`.
+    /// Defaults to the current directory. Use [`RootResolver::find_markers`] to walk up
+    /// to a directory containing `uhuh.toml` or `.git` instead.
+    pub fn root_resolver(mut self, resolver: RootResolver) -> Self {
+        self.phase.root_resolver = resolver;
+        self
+    }
+
+    pub fn set_root_resolver(&mut self, resolver: RootResolver) -> &mut Self {
+        self.phase.root_resolver = resolver;
+        self
+    }
+
+    /// Register a custom mode, like `staging` or `test`, that can be selected with `--mode`.
+    pub fn custom_mode(mut self, name: impl AsRef<str>) -> Result<Self, Error> {
+        self.add_custom_mode(name)?;
+        Ok(self)
+    }
+
+    pub fn add_custom_mode(&mut self, name: impl AsRef<str>) -> Result<&mut Self, Error> {
+        let mode = name.as_ref().parse::<Mode>().map_err(Error::new)?;
+        if !self.phase.modes.contains(&mode) {
+            self.phase.modes.push(mode);
+        }
+        Ok(self)
+    }
+
     pub fn config_pattern(mut self, pattern: impl ToString) -> Self {
         self.phase
             .config_builder
@@ -84,11 +138,134 @@ where
         self
     }
 
+    /// Let environment variables such as `{PREFIX}__DATABASE__URL` override config keys.
+    /// The environment is applied after all other config sources.
+    pub fn config_env_prefix(mut self, prefix: impl ToString) -> Self {
+        self.phase.config_builder.set_env_prefix(prefix.to_string());
+        self
+    }
+
+    pub fn set_config_env_prefix(&mut self, prefix: impl ToString) -> &mut Self {
+        self.phase.config_builder.set_env_prefix(prefix.to_string());
+        self
+    }
+
+    pub fn config_env_separator(mut self, separator: impl ToString) -> Self {
+        self.phase
+            .config_builder
+            .set_env_separator(separator.to_string());
+        self
+    }
+
+    pub fn set_config_env_separator(&mut self, separator: impl ToString) -> &mut Self {
+        self.phase
+            .config_builder
+            .set_env_separator(separator.to_string());
+        self
+    }
+
+    /// Resolve `${scheme:key}` references in config strings with `provider`.
+    /// `env` and `file` are built in. A reference that can not be resolved fails the build.
+    pub fn secret_provider<T: SecretProvider + 'static>(
+        mut self,
+        scheme: &str,
+        provider: T,
+    ) -> Self {
+        self.add_secret_provider(scheme, provider);
+        self
+    }
+
+    pub fn add_secret_provider<T: SecretProvider + 'static>(
+        &mut self,
+        scheme: &str,
+        provider: T,
+    ) -> &mut Self {
+        self.phase
+            .config_builder
+            .add_secret_provider(scheme, Arc::new(provider));
+        self
+    }
+
     pub fn skip_missing_config(mut self, on: bool) -> Self {
         self.phase.skip_on_missing_config = on;
         self
     }
 
+    /// How long each module's shutdown hook may run before it is abandoned.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.phase.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.phase.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Keep the config sources around after build, so the config can be reloaded
+    /// through [`Shutdown::take_config_watcher`]. With the `watch` feature, `cli`
+    /// reloads automatically when a config file changes.
+    pub fn watch_config(mut self, on: bool) -> Self {
+        self.phase.watch_config = on;
+        self
+    }
+
+    pub fn set_watch_config(&mut self, on: bool) -> &mut Self {
+        self.phase.watch_config = on;
+        self
+    }
+
+    /// Build modules that do not depend on each other concurrently. Only modules added with
+    /// [`concurrent_module`](Self::concurrent_module) take part, every other module is built on its own.
+    ///
+    /// Concurrent builds run on the current task, so they only overlap while waiting on I/O.
+    /// Blocking or CPU-heavy work should be moved to the executor with
+    /// [`SharedBuildCtx::spawn_blocking`](crate::SharedBuildCtx::spawn_blocking).
+    /// Values registered by each module are merged back in dependency order once its
+    /// group has finished, so the result is the same as a sequential build.
+    pub fn concurrent_build(mut self, on: bool) -> Self {
+        self.phase.concurrent_build = on;
+        self
+    }
+
+    pub fn set_concurrent_build(&mut self, on: bool) -> &mut Self {
+        self.phase.concurrent_build = on;
+        self
+    }
+
+    /// Record how long each module phase takes. The [`StartupReport`] is registered
+    /// in the `Uhuh` extensions.
+    pub fn startup_report(mut self, on: bool) -> Self {
+        self.set_startup_report(on);
+        self
+    }
+
+    pub fn set_startup_report(&mut self, on: bool) -> &mut Self {
+        self.phase.report = on.then(StartupReport::default);
+        self
+    }
+
+    /// Pin the registered value of `T`. Later registrations of `T` from modules,
+    /// plugins and initializers are ignored, so a fake can replace what a module
+    /// registers without changing the module.
+    pub fn override_ext<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.add_override_ext(value);
+        self
+    }
+
+    pub fn add_override_ext<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
+        let phase = &mut self.phase;
+        phase
+            .overrides
+            .pin(value, &mut phase.extensions, &mut phase.providers);
+        self
+    }
+
+    /// A handle to the startup report, if enabled. It fills up as the app is built.
+    pub fn report(&self) -> Option<StartupReport> {
+        self.phase.report.clone()
+    }
+
     pub fn configure<T>(mut self, func: T) -> Self
     where
         T: Configure + Send + 'static,
@@ -121,23 +298,66 @@ where
     }
 
     pub fn add_module<T: Module<C> + 'static>(&mut self) -> &mut Self {
-        if !self.phase.module_map.contains(&TypeId::of::<T>()) {
-            self.phase.modules.push(box_module::<T, C>());
-            self.phase.module_map.insert(TypeId::of::<T>());
-        }
+        self.phase.graph.insert::<T>();
+        self
+    }
+
+    /// Add a module that is built at the same time as the modules it does not depend on,
+    /// when [`concurrent_build`](Self::concurrent_build) is enabled.
+    pub fn concurrent_module<T: ConcurrentModule<C> + 'static>(mut self) -> Self {
+        self.add_concurrent_module::<T>();
+        self
+    }
+
+    pub fn add_concurrent_module<T: ConcurrentModule<C> + 'static>(&mut self) -> &mut Self {
+        self.phase.graph.insert_concurrent::<T>();
         self
     }
 
+    /// Add a named instance of `T` that reads its config from the section `name`,
+    /// like `db.replica`. A module type can be added under any number of names.
+    pub fn module_named<T: Module<C> + 'static>(mut self, name: &str) -> Result<Self, Error> {
+        self.add_module_named::<T>(name)?;
+        Ok(self)
+    }
+
+    pub fn add_module_named<T: Module<C> + 'static>(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut Self, Error> {
+        self.phase.graph.insert_named::<T>(name)?;
+        Ok(self)
+    }
+
+    /// The modules added so far. Modules added by other modules' setup
+    /// are listed by [`Builder<Build<C>>::modules`] once setup has run.
+    pub fn modules(&self) -> Vec<ModuleInfo> {
+        self.phase.graph.infos()
+    }
+
     pub async fn setup(self) -> Result<Builder<Build<C>>, Error> {
         Ok(Builder {
             phase: self.phase.next().await?,
         })
     }
 
-    pub async fn build(self) -> Result<C::Output, Error> {
+    pub async fn build(self) -> Result<(C::Output, Shutdown<C>), Error> {
         self.setup().await?.build_app().await
     }
 
+    /// Rename or disable the global flags added by [`cli`](Self::cli)
+    #[cfg(feature = "cli")]
+    pub fn cli_flags(mut self, flags: CliFlags) -> Self {
+        self.phase.cli_flags = flags;
+        self
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn set_cli_flags(&mut self, flags: CliFlags) -> &mut Self {
+        self.phase.cli_flags = flags;
+        self
+    }
+
     #[cfg(feature = "cli")]
     pub async fn cli<T>(self, run: T) -> Result<(), Error>
     where
@@ -149,113 +369,125 @@ where
 
 pub struct Setup<C> {
     ctx: C,
-    modules: Vec<Box<dyn DynamicModule<C>>>,
+    graph: ModuleGraph<C>,
     initializers: Vec<Box<dyn Initializer<C>>>,
     mode: Mode,
+    modes: Vec<Mode>,
     name: String,
     skip_on_missing_config: bool,
     root: Option<PathBuf>,
+    root_resolver: RootResolver,
     config_builder: ConfigBuilder,
-    module_map: HashSet<TypeId>,
     executor: AnyExecutor,
     plugins: PluginsList<C>,
+    extensions: Extensions,
+    providers: Providers,
+    overrides: Overrides,
+    named: NamedExtensions,
+    defaults: Config,
+    shutdown_timeout: Duration,
+    watch_config: bool,
+    concurrent_build: bool,
+    report: Option<StartupReport>,
+    #[cfg(feature = "cli")]
+    cmds: Vec<Cmd<C>>,
+    #[cfg(feature = "cli")]
+    cli_flags: CliFlags,
 }
 
-impl<C: Context> Phase for Setup<C> {
-    type Next = Build<C>;
-    fn next(mut self) -> impl Future<Output = Result<Self::Next, Error>> {
-        async move {
-            let mut config = Config::default();
-            let mut extensions = Extensions::default();
-            let mut modules = Vec::default();
-
-            #[cfg(feature = "cli")]
-            let mut cmds = Vec::default();
-            for module in &self.modules {
-                #[cfg(feature = "cli")]
-                let mut cmd = None;
-
-                debug!(module = ?module.config_section(), "Setup module");
-                module.setup(SetupCtx {
-                    ctx: &mut self.ctx,
-                    module_name: module.config_section(),
-                    #[cfg(feature = "cli")]
-                    cmds: &mut cmd,
-                    extensions: &mut extensions,
-                    extra_modules: &mut modules,
-                    module_map: &mut self.module_map,
-                    plugins: &mut self.plugins,
-                })?;
+impl<C: Context> Setup<C> {
+    fn setup_module(&mut self, idx: usize) -> Result<(), Error> {
+        let Some(module) = self.graph.begin(idx) else {
+            return Ok(());
+        };
+
+        let module_name = module.config_section();
+        let instance = module.instance();
+
+        let report = self.report.clone();
+        let ret = report::phase(report.as_ref(), module_name, ModulePhase::Setup, || {
+            debug!(module = ?module_name, "Setup module");
+            module.setup(SetupCtx {
+                module_name,
+                instance,
+                setup: self,
+            })
+        });
 
-                #[cfg(feature = "cli")]
-                if let Some(cmd) = cmd {
-                    debug!(module = ?module.config_section(), "Adding command");
-                    cmds.push(cmd);
-                }
-
-                if let Some(cfg) = module.default_config() {
-                    debug!(module = ?module.config_section(), cfg = ?cfg, "Setting default config");
-                    config.set(module.config_section(), cfg);
-                }
+        let ret = ret.and_then(|_| match module.default_config() {
+            Some(cfg) => {
+                debug!(module = ?module_name, cfg = ?cfg, "Setting default config");
+                config::set_section(&mut self.defaults, module_name, cfg)
             }
+            None => Ok(()),
+        });
 
-            let mut extra_modules: VecDeque<Box<dyn DynamicModule<C>>> = VecDeque::default();
-
-            loop {
-                let Some(module) = modules.pop() else {
-                    break;
-                };
-
-                #[cfg(feature = "cli")]
-                let mut cmd = None;
-                debug!(module = ?module.config_section(), "Setup module");
-                module.setup(SetupCtx {
-                    ctx: &mut self.ctx,
-                    module_name: module.config_section(),
-                    #[cfg(feature = "cli")]
-                    cmds: &mut cmd,
-                    extensions: &mut extensions,
-                    extra_modules: &mut modules,
-                    module_map: &mut self.module_map,
-                    plugins: &mut self.plugins,
-                })?;
-
-                #[cfg(feature = "cli")]
-                if let Some(cmd) = cmd {
-                    debug!(module = ?module.config_section(), "Adding command");
-                    cmds.push(cmd);
-                }
+        self.graph.complete(idx, module);
 
-                if let Some(cfg) = module.default_config() {
-                    debug!(module = ?module.config_section(), cfg = ?cfg, "Setting default config");
-                    config.set(module.config_section(), cfg);
-                }
+        ret
+    }
+}
 
-                extra_modules.push_front(module);
+impl<C: Context> Phase for Setup<C> {
+    type Next = Build<C>;
+    fn next(mut self) -> impl Future<Output = Result<Self::Next, Error>> {
+        async move {
+            while let Some(idx) = self.graph.next_pending() {
+                self.setup_module(idx)?;
             }
 
-            extra_modules.extend(self.modules);
+            for (section, cfg) in self.plugins.default_configs() {
+                debug!(plugin = ?section, cfg = ?cfg, "Setting default config");
+                config::set_section(&mut self.defaults, section, cfg)?;
+            }
 
+            let defaults = self.defaults;
             self.config_builder
                 .add_configure(Box::new(move |cfg: &mut Config| {
-                    cfg.extend(config);
+                    cfg.extend(defaults);
                     Ok(())
                 }));
 
+            let mut depths = Vec::default();
+            let mut deps = Vec::default();
+            let mut modules = Vec::default();
+            let mut infos = Vec::default();
+
+            for sorted in self.graph.into_sorted()? {
+                depths.push(sorted.depth);
+                deps.push(sorted.deps);
+                modules.push(sorted.module);
+                infos.push(sorted.info);
+            }
+
             Ok(Build {
                 ctx: self.ctx,
-                modules: Vec::from_iter(extra_modules),
+                modules,
+                depths,
+                deps,
+                states: ModuleStates::new(infos),
                 initializers: self.initializers,
                 #[cfg(feature = "cli")]
-                cmds,
-                extensions,
+                cmds: self.cmds,
+                #[cfg(feature = "cli")]
+                cli_flags: self.cli_flags,
+                extensions: self.extensions,
+                providers: self.providers,
+                overrides: self.overrides,
+                named: self.named,
                 config: self.config_builder,
                 mode: self.mode,
+                modes: self.modes,
                 name: self.name,
                 skip_on_missing_config: self.skip_on_missing_config,
                 root: self.root,
+                root_resolver: self.root_resolver,
                 executor: self.executor,
                 plugins: self.plugins,
+                shutdown_timeout: self.shutdown_timeout,
+                watch_config: self.watch_config,
+                concurrent_build: self.concurrent_build,
+                report: self.report,
             })
         }
     }
@@ -263,55 +495,202 @@ impl<C: Context> Phase for Setup<C> {
 
 pub struct SetupCtx<'a, C> {
     module_name: &'a str,
-    #[allow(unused)]
-    ctx: &'a mut C,
-    #[cfg(feature = "cli")]
-    cmds: &'a mut Option<Cmd<C>>,
-    extensions: &'a mut Extensions,
-    module_map: &'a mut HashSet<TypeId>,
-    plugins: &'a mut PluginsList<C>,
-    extra_modules: &'a mut Vec<Box<dyn DynamicModule<C>>>,
+    instance: Option<&'a str>,
+    setup: &'a mut Setup<C>,
 }
 
 impl<'a, C: Context> SetupCtx<'a, C> {
+    /// Add a command to the group named after the module, so `migrate` from
+    /// the module `db` runs as `app db migrate`. A module can add any number of commands.
+    /// Fails if the module already added a command with the same name.
     #[cfg(feature = "cli")]
-    pub fn cmd<A>(&mut self, cmd: clap::Command, action: A) -> &mut Self
+    pub fn cmd<A>(&mut self, cmd: clap::Command, action: A) -> Result<&mut Self, Error>
     where
         A: CmdAction<C> + 'static,
         C: 'static,
     {
-        let name = self.module_name.to_string();
-        *self.cmds = Some(Cmd {
-            cmd: cmd.name(name),
-            action: box_action(action),
-        });
-        self
+        self.nested_cmd(&[], cmd, action)
+    }
+
+    /// Add a command below nested groups in the module group,
+    /// like `app db seed users` with `parents` set to `["seed"]`.
+    #[cfg(feature = "cli")]
+    pub fn nested_cmd<A>(
+        &mut self,
+        parents: &[&str],
+        cmd: clap::Command,
+        action: A,
+    ) -> Result<&mut Self, Error>
+    where
+        A: CmdAction<C> + 'static,
+        C: 'static,
+    {
+        self.push_cmd(parents, cmd, CmdRun::Init(box_action(action)))
+    }
+
+    /// Add a command that runs right after setup, with the config loaded but no module built.
+    /// The action receives the [`Uhuh`](crate::Uhuh) with the values registered during setup.
+    /// Suits commands like `version` or `config check` that must not start anything.
+    #[cfg(feature = "cli")]
+    pub fn setup_cmd<A>(&mut self, cmd: clap::Command, action: A) -> Result<&mut Self, Error>
+    where
+        A: CmdAction<()> + 'static,
+    {
+        self.nested_setup_cmd(&[], cmd, action)
+    }
+
+    /// Like [`setup_cmd`](Self::setup_cmd), below nested groups as with [`nested_cmd`](Self::nested_cmd)
+    #[cfg(feature = "cli")]
+    pub fn nested_setup_cmd<A>(
+        &mut self,
+        parents: &[&str],
+        cmd: clap::Command,
+        action: A,
+    ) -> Result<&mut Self, Error>
+    where
+        A: CmdAction<()> + 'static,
+    {
+        self.push_cmd(parents, cmd, CmdRun::Setup(box_action(action)))
+    }
+
+    /// Add a command that runs once all modules are built, before any is initialized.
+    /// The action receives the [`Uhuh`](crate::Uhuh) with the values registered during build,
+    /// like a database pool for `migrate`. Init and finish do not run, but the modules that
+    /// were built are shut down when the command completes or the process is terminated.
+    #[cfg(feature = "cli")]
+    pub fn build_cmd<A>(&mut self, cmd: clap::Command, action: A) -> Result<&mut Self, Error>
+    where
+        A: CmdAction<()> + 'static,
+    {
+        self.nested_build_cmd(&[], cmd, action)
+    }
+
+    /// Like [`build_cmd`](Self::build_cmd), below nested groups as with [`nested_cmd`](Self::nested_cmd)
+    #[cfg(feature = "cli")]
+    pub fn nested_build_cmd<A>(
+        &mut self,
+        parents: &[&str],
+        cmd: clap::Command,
+        action: A,
+    ) -> Result<&mut Self, Error>
+    where
+        A: CmdAction<()> + 'static,
+    {
+        self.push_cmd(parents, cmd, CmdRun::Build(box_action(action)))
+    }
+
+    #[cfg(feature = "cli")]
+    fn push_cmd(
+        &mut self,
+        parents: &[&str],
+        cmd: clap::Command,
+        action: CmdRun<C>,
+    ) -> Result<&mut Self, Error> {
+        let path = core::iter::once(self.module_name)
+            .chain(parents.iter().copied())
+            .chain([cmd.get_name()])
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+
+        if self.setup.cmds.iter().any(|other| other.path == path) {
+            return Err(Error::new(format!(
+                "command '{}' is already defined",
+                path.join(" ")
+            )));
+        }
+
+        self.setup.cmds.push(Cmd { path, cmd, action });
+        Ok(self)
     }
 
     pub fn name(&self) -> &str {
         self.module_name
     }
 
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.instance
+    }
+
+    /// Register a value. Values registered by a named instance are stored under
+    /// the instance name, see `get_named`.
     pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
-        self.extensions.insert(value);
+        if self.setup.overrides.ignores::<T>(self.module_name) {
+            return self;
+        }
+
+        match self.instance {
+            Some(name) => self.setup.named.insert(name, value),
+            None => {
+                self.setup.providers.insert::<T>(self.module_name);
+                self.setup.extensions.insert(value);
+            }
+        }
+        self
+    }
+
+    /// Declares that this module registers `T` during build or init.
+    /// `require::<T>()` names this module if `T` is missing.
+    pub fn provides<T: Send + Sync + 'static>(&mut self) -> &mut Self {
+        self.setup.providers.insert::<T>(self.module_name);
         self
     }
 
+    /// Add `T` if it is not registered yet. It is built, initialized and finished
+    /// before this module, like a module added with [`depends_on`](Self::depends_on).
     pub fn add_module<T: Module<C> + 'static>(&mut self) -> &mut Self {
-        if !self.module_map.contains(&TypeId::of::<T>()) {
-            self.extra_modules.push(box_module::<T, C>());
-            self.module_map.insert(TypeId::of::<T>());
-        }
+        self.setup.graph.insert_child::<T>();
         self
     }
 
+    /// Add a named instance of `T` that reads its config from the section `name`.
+    /// It is built before this module.
+    pub fn add_module_named<T: Module<C> + 'static>(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut Self, Error> {
+        self.setup.graph.insert_named_child::<T>(name)?;
+        Ok(self)
+    }
+
+    /// Declares that this module depends on `T`, adding `T` if it is not registered yet.
+    ///
+    /// `T` is set up before this call returns, and is built, initialized and finished
+    /// before this module. Fails if `T` (transitively) depends on this module.
+    pub fn depends_on<T: Module<C> + 'static>(&mut self) -> Result<&mut Self, Error> {
+        let idx = self.setup.graph.insert::<T>();
+        if self.setup.graph.check_dependency(idx)? {
+            self.setup.setup_module(idx)?;
+        }
+        self.setup.graph.add_dependency(idx);
+        Ok(self)
+    }
+
     pub fn register_plugin<T>(&mut self, plugin: T) -> Result<&mut Self, Error>
     where
         T: 'static + Plugin<C> + Send + Sync,
         T::Output: Send + Sync + 'static,
         T::Error: 'static,
     {
-        self.plugins.insert(plugin)?;
+        self.setup.plugins.insert(plugin)?;
+        self.setup
+            .providers
+            .insert::<T::Output>(core::any::type_name::<T>());
+
+        Ok(self)
+    }
+
+    /// Register a plugin that reads its own config section, see [`ConfiguredPlugin`]
+    pub fn register_configured_plugin<T>(&mut self, plugin: T) -> Result<&mut Self, Error>
+    where
+        T: 'static + ConfiguredPlugin<C> + Send + Sync,
+        T::Output: Send + Sync + 'static,
+        T::Error: 'static,
+    {
+        self.setup.plugins.insert_configured(plugin)?;
+        self.setup
+            .providers
+            .insert::<T::Output>(core::any::type_name::<T>());
 
         Ok(self)
     }
@@ -0,0 +1,426 @@
+use std::{
+    future::{poll_fn, Future},
+    path::{Path, PathBuf},
+    pin::pin,
+    rc::Rc,
+    sync::mpsc::RecvTimeoutError,
+    task::Poll,
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+use crate::{module::DynamicModule, named::Lifecycle, Error, Mode};
+
+use super::ConfigWatcher;
+
+/// Handle returned from a finished build, used to tear the application down.
+///
+/// Modules are shut down in reverse build order. A module that fails or does not
+/// finish within the timeout is logged, and the remaining modules are still shut down.
+/// Only modules that were built are shut down, so a module skipped for a missing
+/// config is left out. If build or init fails, the modules built so far are shut
+/// down before the error is returned.
+pub struct Shutdown<C> {
+    pub(super) modules: Rc<Vec<Box<dyn DynamicModule<C>>>>,
+    pub(super) lifecycle: Rc<Lifecycle>,
+    pub(super) mode: Mode,
+    pub(super) root: PathBuf,
+    pub(super) timeout: Duration,
+    pub(super) watcher: Option<ConfigWatcher<C>>,
+}
+
+impl<C> Shutdown<C> {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// The config watcher, if the builder was configured with `watch_config`.
+    /// It has to be polled alongside the application for reloads to happen.
+    pub fn take_config_watcher(&mut self) -> Option<ConfigWatcher<C>> {
+        self.watcher.take()
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn run(self) -> Result<(), Error> {
+        let failed = shutdown_modules(
+            self.modules.iter(),
+            &self.lifecycle,
+            &self.mode,
+            &self.root,
+            self.timeout,
+        )
+        .await;
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::new(format!(
+                "shutdown failed for: {}",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    /// Runs `action` until it completes or the process receives Ctrl-C/SIGTERM,
+    /// then shuts the application down.
+    #[cfg(feature = "cli")]
+    pub(crate) async fn run_until_signal<F>(mut self, action: F) -> Result<(), Error>
+    where
+        F: Future<Output = Result<(), Error>>,
+    {
+        let watcher = self.take_config_watcher();
+        let action = background(action, async move {
+            #[cfg(feature = "watch")]
+            if let Some(watcher) = watcher {
+                if let Err(err) = watcher.run().await {
+                    warn!(error = %err, "Config watcher stopped");
+                }
+            }
+            #[cfg(not(feature = "watch"))]
+            drop(watcher);
+        });
+
+        let ret = match race(action, termination_signal()).await {
+            Some(ret) => ret,
+            None => {
+                tracing::info!("Received termination signal. Shutting down");
+                Ok(())
+            }
+        };
+
+        let shutdown = self.run().await;
+
+        ret.and(shutdown)
+    }
+}
+
+/// Runs the shutdown hook of each module in reverse order, returning the failures.
+async fn shutdown_modules<'a, C: 'a>(
+    modules: impl DoubleEndedIterator<Item = &'a Box<dyn DynamicModule<C>>>,
+    lifecycle: &Lifecycle,
+    mode: &Mode,
+    root: &Path,
+    timeout: Duration,
+) -> Vec<String> {
+    let mut failed = Vec::default();
+
+    for module in modules.rev() {
+        let section = module.config_section();
+        debug!(module = ?section, "Shutdown module");
+
+        let ctx = ShutdownCtx {
+            lifecycle,
+            instance: module.instance(),
+            mode,
+            root,
+        };
+
+        match race(module.shutdown(ctx), sleep(timeout)).await {
+            Some(Ok(())) => {}
+            Some(Err(err)) => {
+                warn!(module = ?section, error = %err, "Shutdown failed");
+                failed.push(format!("{section}: {err}"));
+            }
+            None => {
+                warn!(module = ?section, timeout = ?timeout, "Shutdown timed out");
+                failed.push(format!("{section}: timed out after {timeout:?}"));
+            }
+        }
+    }
+
+    failed
+}
+
+/// Shuts down the modules that were built before a later build or init step failed.
+/// Returns `err`, the failure that stopped the startup.
+pub(super) async fn abort<'a, C: 'a>(
+    err: Error,
+    modules: impl DoubleEndedIterator<Item = &'a Box<dyn DynamicModule<C>>>,
+    lifecycle: &Lifecycle,
+    mode: &Mode,
+    root: &Path,
+    timeout: Duration,
+) -> Error {
+    warn!(error = %err, "Startup failed. Shutting down the modules that were built");
+    shutdown_modules(modules, lifecycle, mode, root, timeout).await;
+    err
+}
+
+pub struct ShutdownCtx<'a> {
+    lifecycle: &'a Lifecycle,
+    instance: Option<&'a str>,
+    mode: &'a Mode,
+    root: &'a Path,
+}
+
+impl<'a> ShutdownCtx<'a> {
+    pub fn mode(&self) -> &Mode {
+        self.mode
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root
+    }
+
+    /// The instance name, if the module was added with `add_module_named`
+    pub fn instance(&self) -> Option<&str> {
+        self.instance
+    }
+
+    /// Get a value the module or instance registered with `register_lifecycle` during build or init.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.lifecycle.get::<T>(self.instance)
+    }
+}
+
+/// Polls `future` to completion while also driving `task` until it completes.
+#[cfg(feature = "cli")]
+async fn background<F, T>(future: F, task: T) -> F::Output
+where
+    F: Future,
+    T: Future<Output = ()>,
+{
+    let mut future = pin!(future);
+    let mut task = pin!(task);
+    let mut task_done = false;
+
+    poll_fn(|cx| {
+        if !task_done {
+            task_done = task.as_mut().poll(cx).is_ready();
+        }
+
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Polls `future` until it completes, or returns `None` if `stop` completes first.
+pub(super) async fn race<F, S>(future: F, stop: S) -> Option<F::Output>
+where
+    F: Future,
+    S: Future<Output = ()>,
+{
+    let mut future = pin!(future);
+    let mut stop = pin!(stop);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(ret) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(ret));
+        }
+
+        match stop.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Completes after `duration`. The timer runs on its own thread, so it works with
+/// any executor and does not keep a runtime from shutting down.
+/// Dropping the future cancels the wait and ends the thread.
+pub(super) fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    let (sx, rx) = futures_channel::oneshot::channel::<()>();
+    let (cancel, cancelled) = std::sync::mpsc::channel::<()>();
+
+    std::thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(duration) {
+            sx.send(()).ok();
+        }
+    });
+
+    async move {
+        let _cancel = cancel;
+        rx.await.ok();
+    }
+}
+
+/// Tasks waiting for Ctrl-C/SIGTERM
+#[cfg(feature = "cli")]
+static SIGNAL_WAITERS: std::sync::Mutex<Vec<futures_channel::oneshot::Sender<()>>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Wakes the tasks waiting for a signal. When nothing is waiting, exits with `code`.
+#[cfg(feature = "cli")]
+fn notify_signal(code: i32) {
+    let waiters = core::mem::take(
+        &mut *SIGNAL_WAITERS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()),
+    );
+
+    let mut notified = false;
+    for sx in waiters {
+        notified |= sx.send(()).is_ok();
+    }
+
+    if !notified {
+        std::process::exit(code);
+    }
+}
+
+/// Forwards SIGINT, SIGTERM and SIGHUP. Exits with `128 + signal`, like a shell does.
+#[cfg(all(feature = "cli", unix))]
+fn install_signal_handler() {
+    use signal_hook::{consts::signal, iterator::Signals};
+
+    let mut signals = match Signals::new([signal::SIGINT, signal::SIGTERM, signal::SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!(error = %err, "Could not install signal handler");
+            return;
+        }
+    };
+
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            notify_signal(128 + signal);
+        }
+    });
+}
+
+#[cfg(all(feature = "cli", not(unix)))]
+fn install_signal_handler() {
+    if let Err(err) = ctrlc::set_handler(|| notify_signal(130)) {
+        warn!(error = %err, "Could not install signal handler");
+    }
+}
+
+/// Completes on the next Ctrl-C/SIGTERM.
+///
+/// The process wide handler is installed once. A signal wakes every task waiting
+/// at that moment. When nothing is waiting, like after shutdown has started or once
+/// the app has returned, the process exits as it would without a handler:
+/// `130` for SIGINT and `143` for SIGTERM.
+#[cfg(feature = "cli")]
+fn termination_signal() -> impl Future<Output = ()> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+
+    INSTALL.call_once(install_signal_handler);
+
+    let (sx, rx) = futures_channel::oneshot::channel::<()>();
+    SIGNAL_WAITERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(sx);
+
+    async move {
+        if rx.await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BuildCtx, Builder, Module};
+    use bobestyrer::Tokio;
+    use std::sync::Mutex;
+
+    #[derive(Default, serde::Serialize, serde::Deserialize)]
+    struct Empty {}
+
+    static ORDER: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    struct First;
+
+    impl Module<()> for First {
+        const CONFIG_SECTION: &'static str = "first";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn shutdown(_: ShutdownCtx<'_>) -> Result<(), Error> {
+            ORDER.lock().unwrap().push("first");
+            Ok(())
+        }
+    }
+
+    struct Second;
+
+    impl Module<()> for Second {
+        const CONFIG_SECTION: &'static str = "second";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        fn setup(mut ctx: crate::SetupCtx<'_, ()>) -> Result<(), Error> {
+            ctx.depends_on::<First>()?;
+            Ok(())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn shutdown(_: ShutdownCtx<'_>) -> Result<(), Error> {
+            ORDER.lock().unwrap().push("second");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_are_shut_down_in_reverse_build_order() {
+        let (_, shutdown) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .module::<Second>()
+            .module::<First>()
+            .build()
+            .await
+            .unwrap();
+
+        shutdown.run().await.unwrap();
+
+        assert_eq!(*ORDER.lock().unwrap(), vec!["second", "first"]);
+    }
+
+    struct Stuck;
+
+    impl Module<()> for Stuck {
+        const CONFIG_SECTION: &'static str = "stuck";
+        type Config = Empty;
+
+        fn default_config() -> Option<Empty> {
+            Some(Empty::default())
+        }
+
+        async fn build(_: BuildCtx<'_, ()>, _: Empty) -> Result<(), Error> {
+            Ok(())
+        }
+
+        async fn shutdown(_: ShutdownCtx<'_>) -> Result<(), Error> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_times_out() {
+        let (_, mut shutdown) = Builder::new((), "test", Mode::Development, Tokio::from_global())
+            .root(std::env::temp_dir())
+            .module::<Stuck>()
+            .build()
+            .await
+            .unwrap();
+
+        shutdown.set_timeout(Duration::from_millis(10));
+        let err = shutdown.run().await.unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "shutdown failed for: stuck: timed out after 10ms"
+        );
+    }
+}
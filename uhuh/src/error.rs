@@ -1,8 +1,17 @@
 use core::fmt;
 
+use serde::de::DeserializeOwned;
+use vaerdi::{de::DeserializerError, Value};
+
+use crate::providers::MissingExtension;
+
 #[derive(Debug)]
-pub struct Error {
-    inner: Box<dyn std::error::Error + Send + Sync>,
+pub enum Error {
+    /// One or more modules have missing or invalid configuration
+    Config(Vec<ConfigError>),
+    /// Invalid command line arguments, like an unknown command or mode
+    Usage(String),
+    Other(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl Error {
@@ -10,21 +19,81 @@ impl Error {
     where
         T: Into<Box<dyn std::error::Error + Send + Sync>>,
     {
-        Self {
-            inner: error.into(),
+        Self::Other(error.into())
+    }
+
+    pub fn config_errors(&self) -> &[ConfigError] {
+        match self {
+            Self::Config(errors) => errors,
+            Self::Usage(_) | Self::Other(_) => &[],
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.inner.fmt(f)
+        match self {
+            Self::Config(errors) if errors.len() == 1 => fmt::Display::fmt(&errors[0], f),
+            Self::Config(errors) => {
+                write!(f, "invalid configuration:")?;
+                for error in errors {
+                    write!(f, "\n  - {error}")?;
+                }
+                Ok(())
+            }
+            Self::Usage(message) => f.write_str(message),
+            Self::Other(inner) => inner.fmt(f),
+        }
     }
 }
 
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(&*self.inner)
+        match self {
+            Self::Config(_) | Self::Usage(_) => None,
+            Self::Other(inner) => Some(&**inner),
+        }
+    }
+}
+
+/// Maps an error to a process exit code, so scripts can tell failures apart.
+///
+/// ```ignore
+/// fn main() -> std::process::ExitCode {
+///     match runtime.block_on(builder.cli(run)) {
+///         Ok(()) => std::process::ExitCode::SUCCESS,
+///         Err(err) => {
+///             eprintln!("{err}");
+///             err.exit_code().into()
+///         }
+///     }
+/// }
+/// ```
+pub trait ExitStatus {
+    fn exit_code(&self) -> u8;
+}
+
+impl ExitStatus for Error {
+    /// `64` for usage errors and `78` for config errors, following `sysexits.h`.
+    /// Any other error is `1`.
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::Usage(_) => 64,
+            Self::Config(_) => 78,
+            Self::Other(_) => 1,
+        }
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(value: ConfigError) -> Self {
+        Self::Config(vec![value])
+    }
+}
+
+impl From<MissingExtension> for Error {
+    fn from(value: MissingExtension) -> Self {
+        Self::new(value)
     }
 }
 
@@ -36,6 +105,173 @@ impl From<vaerdi::ser::SerializerError> for Error {
 
 impl From<johnfig::Error> for Error {
     fn from(value: johnfig::Error) -> Self {
-        Self::new(value)
+        config_source_error("config", value)
+    }
+}
+
+/// A config source that could not be read, like a file without read permission, is a runtime
+/// error. Anything else a source fails with is a parse or merge error, reported as a config error.
+pub(crate) fn config_source_error<E>(source: impl fmt::Display, error: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if is_io(&error) {
+        Error::new(error)
+    } else {
+        ConfigError::load(source, error).into()
+    }
+}
+
+/// Whether `error` or one of its sources is an IO error
+fn is_io(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if error.is::<std::io::Error>() {
+            return true;
+        }
+        current = error.source();
+    }
+    false
+}
+
+/// A module's config section is missing or does not match its `Config` type.
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    /// The module's `CONFIG_SECTION`, or the file that failed to load
+    pub section: String,
+    /// Path to the failing field inside the section, if known
+    pub path: Option<String>,
+    /// The type the deserializer expected, if known
+    pub expected: Option<String>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn missing(section: &str) -> ConfigError {
+        ConfigError {
+            section: section.to_string(),
+            path: None,
+            expected: None,
+            message: "config not set".to_string(),
+        }
+    }
+
+    /// A config file or source could not be read or parsed
+    pub fn load(source: impl fmt::Display, error: impl fmt::Display) -> ConfigError {
+        ConfigError {
+            section: source.to_string(),
+            path: None,
+            expected: None,
+            message: error.to_string(),
+        }
+    }
+
+    pub(crate) fn deserialize<T: DeserializeOwned>(
+        section: &str,
+        value: &Value,
+    ) -> Result<T, ConfigError> {
+        serde_path_to_error::deserialize::<_, T>(value.clone()).map_err(|err| {
+            let path = Some(err.path().to_string()).filter(|path| path != ".");
+            let err = err.into_inner();
+
+            let expected = match &err {
+                DeserializerError::InvalidType(_, expected)
+                | DeserializerError::InvalidValue(_, expected)
+                | DeserializerError::InvalidLength(_, expected) => Some(expected.clone()),
+                _ => None,
+            };
+
+            ConfigError {
+                section: section.to_string(),
+                path,
+                expected,
+                message: err.to_string(),
+            }
+        })
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}.{}: {}", self.section, path, self.message),
+            None => write!(f, "{}: {}", self.section, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(unused)]
+    struct Server {
+        listen: Listen,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    #[allow(unused)]
+    struct Listen {
+        port: u16,
+    }
+
+    #[test]
+    fn deserialize_reports_path_and_expected() {
+        let value =
+            vaerdi::ser::to_value(serde_json::json!({ "listen": { "port": "http" } })).unwrap();
+
+        let err = ConfigError::deserialize::<Server>("server", &value).unwrap_err();
+
+        assert_eq!(err.section, "server");
+        assert_eq!(err.path.as_deref(), Some("listen.port"));
+        assert_eq!(err.expected.as_deref(), Some("u16"));
+    }
+
+    #[test]
+    fn deserialize_missing_field_has_no_expected() {
+        let value = vaerdi::ser::to_value(serde_json::json!({ "listen": {} })).unwrap();
+
+        let err = ConfigError::deserialize::<Server>("server", &value).unwrap_err();
+
+        assert_eq!(err.path.as_deref(), Some("listen"));
+        assert_eq!(err.expected, None);
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(std::io::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "could not read config")
+        }
+    }
+
+    impl std::error::Error for Wrapped {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn only_parse_errors_of_config_sources_are_config_errors() {
+        let io = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+
+        assert_eq!(config_source_error("app.toml", io()).exit_code(), 1);
+        assert_eq!(
+            config_source_error("app.toml", Wrapped(io())).exit_code(),
+            1
+        );
+        assert_eq!(config_source_error("app.toml", fmt::Error).exit_code(), 78);
+    }
+
+    #[test]
+    fn load_errors_are_config_errors() {
+        let err = Error::from(ConfigError::load("app.toml", "expected a table"));
+
+        assert_eq!(err.exit_code(), 78);
+        assert_eq!(err.to_string(), "app.toml: expected a table");
     }
 }
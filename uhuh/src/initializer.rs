@@ -1,12 +1,35 @@
-use std::{future::Future, pin::Pin};
+use std::{borrow::Cow, future::Future, ops::AsyncFn, pin::Pin};
 
 use crate::{builder::InitCtx, Error};
 
+/// When an initializer runs relative to the module `init` calls
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum InitOrder {
+    /// Before any module is initialized
+    BeforeModules,
+    /// After all modules are initialized
+    #[default]
+    AfterModules,
+    /// Right before the module with the given config section is initialized
+    Before(String),
+    /// Right after the module with the given config section is initialized
+    After(String),
+}
+
 pub trait Initializer<C> {
     fn call<'a>(
         &'a self,
         core: InitCtx<'a, C>,
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+
+    /// Used for logging, and as the provider of values it registers
+    fn name(&self) -> &str {
+        "initializer"
+    }
+
+    fn order(&self) -> InitOrder {
+        InitOrder::AfterModules
+    }
 }
 
 impl<T, C> Initializer<C> for T
@@ -20,3 +43,116 @@ where
         Box::pin(async move { (self)(core) })
     }
 }
+
+/// Wraps an async closure as an [`Initializer`]. The returned future may borrow the context.
+///
+/// ```ignore
+/// builder.initializer(async_initializer(async |ctx: InitCtx<'_, C>| {
+///     let pool = ctx.require::<Pool>()?;
+///     pool.migrate().await
+/// }));
+/// ```
+pub fn async_initializer<C, F>(func: F) -> AsyncInitializer<F>
+where
+    F: AsyncFn(InitCtx<'_, C>) -> Result<(), Error>,
+{
+    AsyncInitializer { func }
+}
+
+pub struct AsyncInitializer<F> {
+    func: F,
+}
+
+impl<C, F> Initializer<C> for AsyncInitializer<F>
+where
+    F: AsyncFn(InitCtx<'_, C>) -> Result<(), Error>,
+{
+    fn call<'a>(
+        &'a self,
+        core: InitCtx<'a, C>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        Box::pin((self.func)(core))
+    }
+}
+
+/// Gives any initializer a name and an [`InitOrder`]
+pub trait InitializerExt<C>: Initializer<C> + Sized {
+    fn named(self, name: impl Into<Cow<'static, str>>) -> Scheduled<Self> {
+        Scheduled::new(self).named(name)
+    }
+
+    fn ordered(self, order: InitOrder) -> Scheduled<Self> {
+        Scheduled::new(self).ordered(order)
+    }
+
+    fn before(self, section: impl ToString) -> Scheduled<Self> {
+        Scheduled::new(self).before(section)
+    }
+
+    fn after(self, section: impl ToString) -> Scheduled<Self> {
+        Scheduled::new(self).after(section)
+    }
+}
+
+impl<T, C> InitializerExt<C> for T where T: Initializer<C> {}
+
+/// An initializer with a name and an [`InitOrder`], see [`InitializerExt`]
+pub struct Scheduled<T> {
+    init: T,
+    name: Option<Cow<'static, str>>,
+    order: Option<InitOrder>,
+}
+
+impl<T> Scheduled<T> {
+    fn new(init: T) -> Scheduled<T> {
+        Scheduled {
+            init,
+            name: None,
+            order: None,
+        }
+    }
+
+    pub fn named(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn ordered(mut self, order: InitOrder) -> Self {
+        self.order = Some(order);
+        self
+    }
+
+    pub fn before(self, section: impl ToString) -> Self {
+        self.ordered(InitOrder::Before(section.to_string()))
+    }
+
+    pub fn after(self, section: impl ToString) -> Self {
+        self.ordered(InitOrder::After(section.to_string()))
+    }
+}
+
+impl<T, C> Initializer<C> for Scheduled<T>
+where
+    T: Initializer<C>,
+{
+    fn call<'a>(
+        &'a self,
+        core: InitCtx<'a, C>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        self.init.call(core)
+    }
+
+    fn name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => self.init.name(),
+        }
+    }
+
+    fn order(&self) -> InitOrder {
+        match &self.order {
+            Some(order) => order.clone(),
+            None => self.init.order(),
+        }
+    }
+}
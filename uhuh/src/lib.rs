@@ -4,18 +4,33 @@ mod error;
 mod initializer;
 mod mode;
 mod module;
+mod named;
+mod overrides;
 mod plugin;
+mod providers;
+mod report;
+mod root;
+mod secrets;
 mod uhuh;
 
 pub use self::{
-    builder::{BuildCtx, Builder, InitCtx, SetupCtx},
+    builder::{
+        BuildCtx, Builder, ConfigWatcher, InitCtx, ReconfigureCtx, SetupCtx, SharedBuildCtx,
+        Shutdown, ShutdownCtx,
+    },
     configure::Configure,
     context::Context,
-    error::Error,
-    initializer::Initializer,
-    mode::Mode,
-    module::Module,
-    plugin::Plugin,
+    error::{ConfigError, Error, ExitStatus},
+    initializer::{
+        async_initializer, AsyncInitializer, InitOrder, Initializer, InitializerExt, Scheduled,
+    },
+    mode::{CustomMode, Mode},
+    module::{ConcurrentModule, Module},
+    plugin::{ConfiguredPlugin, Plugin, PluginCtx, PluginDeps},
+    providers::MissingExtension,
+    report::{ModuleInfo, ModulePhase, Outcome, ReportEntry, StartupReport},
+    root::{RootResolver, RootSearch, RootSource},
+    secrets::{EnvProvider, FileProvider, SecretProvider},
     uhuh::Uhuh,
 };
 
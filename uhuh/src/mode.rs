@@ -3,33 +3,82 @@ use std::str::FromStr;
 
 use vaerdi::{convert::FromValue, ConvertError, Value};
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Mode {
-    #[serde(
-        rename = "development",
-        alias = "Development",
-        alias = "dev",
-        alias = "Dev"
-    )]
     Development,
-    #[serde(
-        rename = "production",
-        alias = "Production",
-        alias = "prod",
-        alias = "Prod"
-    )]
     Production,
+    /// A user defined mode, like `staging` or `test`.
+    /// Custom modes must be registered on the builder before they can be selected.
+    Custom(CustomMode),
 }
 
+/// The name of a custom mode, always lowercase
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CustomMode(String);
+
+impl CustomMode {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CustomMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Mode {
+    /// A custom mode. The name may only contain letters, digits, `-` and `_`,
+    /// and can not be the name of a built-in mode.
+    pub fn custom(name: impl AsRef<str>) -> Result<Mode, ModeParseErr> {
+        match name.as_ref().parse()? {
+            Mode::Custom(name) => Ok(Mode::Custom(name)),
+            _ => Err(ModeParseErr),
+        }
+    }
+
+    /// The canonical, lowercase name of the mode, used when the mode is serialized
+    /// and parsed, and as `{mode}` in config file names like `config.development.yaml`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Mode::Development => "development",
+            Mode::Production => "production",
+            Mode::Custom(name) => name.as_str(),
+        }
+    }
+}
+
+/// `Development` and `Production` are capitalized, custom modes are lowercase.
 impl fmt::Display for Mode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Mode::Development => f.write_str("Development"),
             Mode::Production => f.write_str("Production"),
+            Mode::Custom(name) => fmt::Display::fmt(name, f),
         }
     }
 }
 
+impl serde::Serialize for Mode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Mode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl FromValue for Mode {
     type Error = ModeParseErr;
     fn from_value(value: vaerdi::Value) -> Result<Self, Self::Error> {
@@ -43,7 +92,7 @@ impl FromValue for Mode {
 
 impl From<Mode> for Value {
     fn from(value: Mode) -> Self {
-        Value::String(value.to_string().into())
+        Value::String(value.as_str().into())
     }
 }
 
@@ -68,11 +117,71 @@ impl FromStr for Mode {
     type Err = ModeParseErr;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mode = match s.to_lowercase().as_str() {
+        let name = s.trim().to_lowercase();
+        let mode = match name.as_str() {
             "development" | "dev" => Mode::Development,
             "production" | "prod" => Mode::Production,
+            "" => return Err(ModeParseErr),
+            custom
+                if custom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                Mode::Custom(CustomMode(custom.to_string()))
+            }
             _ => return Err(ModeParseErr),
         };
         Ok(mode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("dev".parse::<Mode>().unwrap(), Mode::Development);
+        assert_eq!("Production".parse::<Mode>().unwrap(), Mode::Production);
+        assert_eq!(
+            " Staging ".parse::<Mode>().unwrap(),
+            Mode::custom("staging").unwrap()
+        );
+        assert!("".parse::<Mode>().is_err());
+        assert!("a/b".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn custom_modes_are_lowercase() {
+        let staging = Mode::custom("Staging").unwrap();
+        assert_eq!(staging.as_str(), "staging");
+        assert_eq!(staging.to_string(), "staging");
+    }
+
+    #[test]
+    fn custom_modes_are_validated() {
+        assert!(Mode::custom("a/b").is_err());
+        assert!(Mode::custom("").is_err());
+        assert!(Mode::custom("dev").is_err());
+        assert!(Mode::custom("blue-green_2").is_ok());
+    }
+
+    #[test]
+    fn pattern_value_is_lowercase() {
+        for mode in [
+            Mode::Development,
+            Mode::Production,
+            Mode::custom("test").unwrap(),
+        ] {
+            assert_eq!(
+                Value::from(mode.clone()),
+                Value::String(mode.as_str().into())
+            );
+        }
+        assert_eq!(Mode::Development.to_string(), "Development");
+        assert_eq!(
+            Value::from(Mode::Development),
+            Value::String("development".into())
+        );
+    }
+}
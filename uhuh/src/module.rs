@@ -1,12 +1,13 @@
 use std::{future::Future, marker::PhantomData, pin::Pin};
 
+use johnfig::Config;
 use vaerdi::Value;
 
 use crate::{
-    builder::{BuildCtx, SetupCtx},
+    builder::{BuildCtx, ReconfigureCtx, SetupCtx, SharedBuildCtx, ShutdownCtx},
     context::Context,
-    error::Error,
-    InitCtx,
+    error::{ConfigError, Error},
+    InitCtx, Mode,
 };
 
 #[allow(unused)]
@@ -17,6 +18,13 @@ pub trait Module<C: Context> {
 
     fn default_config() -> Option<Self::Config>;
 
+    /// Decides whether the module is built. Disabled modules skip all phases after setup.
+    /// The module can also be disabled with `enabled: false` in its config section.
+    /// Modules depending on a disabled module are disabled too.
+    fn is_enabled(config: &Config, mode: &Mode) -> bool {
+        true
+    }
+
     fn setup(ctx: SetupCtx<'_, C>) -> Result<(), Error> {
         Ok(())
     }
@@ -31,21 +39,112 @@ pub trait Module<C: Context> {
     fn finish(ctx: &mut C::Output) -> impl Future<Output = Result<(), Error>> {
         async move { Ok(()) }
     }
+
+    fn shutdown(ctx: ShutdownCtx<'_>) -> impl Future<Output = Result<(), Error>> {
+        async move { Ok(()) }
+    }
+
+    /// Called when the module's config section changes while the app is running.
+    /// Returns `false` if the module cannot apply the new config and keeps running with the old one.
+    fn reconfigure(
+        ctx: ReconfigureCtx<'_>,
+        config: Self::Config,
+    ) -> impl Future<Output = Result<bool, Error>> {
+        async move { Ok(false) }
+    }
+}
+
+/// A module that can be built at the same time as the modules it does not depend on.
+///
+/// Added with [`Builder::concurrent_module`](crate::Builder::concurrent_module). It is only
+/// built concurrently when `concurrent_build` is enabled, otherwise [`Module::build`] is used.
+/// `build` can call `Self::build_concurrent(ctx.shared(), config)` to share the implementation.
+pub trait ConcurrentModule<C: Context>: Module<C> {
+    /// Build the module with shared access to the context
+    fn build_concurrent(
+        ctx: SharedBuildCtx<'_, C>,
+        config: Self::Config,
+    ) -> impl Future<Output = Result<(), Error>>;
+}
+
+/// How a boxed module is built, either sequentially or with [`ConcurrentModule`]
+pub trait BuildMode<T, C: Context> {
+    const CONCURRENT: bool;
+
+    fn build_concurrent<'a>(
+        section: &'a str,
+        ctx: SharedBuildCtx<'a, C>,
+        value: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+}
+
+pub struct Sequential;
+
+impl<T: Module<C>, C: Context> BuildMode<T, C> for Sequential {
+    const CONCURRENT: bool = false;
+
+    fn build_concurrent<'a>(
+        section: &'a str,
+        _: SharedBuildCtx<'a, C>,
+        _: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        Box::pin(async move {
+            Err(Error::new(format!(
+                "module '{section}' can not be built concurrently"
+            )))
+        })
+    }
+}
+
+pub struct Concurrent;
+
+impl<T: ConcurrentModule<C>, C: Context> BuildMode<T, C> for Concurrent {
+    const CONCURRENT: bool = true;
+
+    fn build_concurrent<'a>(
+        section: &'a str,
+        ctx: SharedBuildCtx<'a, C>,
+        value: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        Box::pin(async move {
+            let cfg = ConfigError::deserialize::<T::Config>(section, &value)?;
+            T::build_concurrent(ctx, cfg).await
+        })
+    }
 }
 
 pub trait DynamicModule<C: Context> {
     fn config_section(&self) -> &str;
 
+    /// The instance name for modules added with `add_module_named`
+    fn instance(&self) -> Option<&str>;
+
+    fn type_name(&self) -> &'static str;
+
     fn default_config(&self) -> Option<Value>;
 
+    fn validate_config(&self, config: &Value) -> Result<(), ConfigError>;
+
+    fn is_enabled(&self, config: &Config, mode: &Mode) -> bool;
+
     fn setup(&self, core: SetupCtx<'_, C>) -> Result<(), Error>;
 
+    /// Whether the module is a [`ConcurrentModule`] that can be built with
+    /// [`build_concurrent`](Self::build_concurrent)
+    fn concurrent(&self) -> bool;
+
     fn build<'a>(
         &'a self,
         ctx: BuildCtx<'a, C>,
         config: Value,
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
 
+    fn build_concurrent<'a>(
+        &'a self,
+        ctx: SharedBuildCtx<'a, C>,
+        config: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+
     fn init<'a>(
         &'a self,
         ctx: InitCtx<'a, C>,
@@ -55,46 +154,108 @@ pub trait DynamicModule<C: Context> {
         &'a self,
         core: &'a mut C::Output,
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+
+    fn shutdown<'a>(
+        &'a self,
+        ctx: ShutdownCtx<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>>;
+
+    fn reconfigure<'a>(
+        &'a self,
+        ctx: ReconfigureCtx<'a>,
+        config: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>>;
 }
 
 pub fn box_module<T: Module<C> + 'static, C: Context>() -> Box<dyn DynamicModule<C>> {
-    Box::new(ModuleDyn(PhantomData::<T>))
+    Box::new(ModuleDyn::<T> {
+        name: None,
+        module: PhantomData,
+    })
 }
 
-pub struct ModuleDyn<T>(PhantomData<T>);
+/// Box a module that is built concurrently when `concurrent_build` is enabled.
+pub fn box_concurrent_module<T: ConcurrentModule<C> + 'static, C: Context>(
+) -> Box<dyn DynamicModule<C>> {
+    Box::new(ModuleDyn::<T, Concurrent> {
+        name: None,
+        module: PhantomData,
+    })
+}
+
+/// Box a named instance of `T`, reading its config from the section `name`.
+pub fn box_named_module<T: Module<C> + 'static, C: Context>(
+    name: impl ToString,
+) -> Box<dyn DynamicModule<C>> {
+    Box::new(ModuleDyn::<T> {
+        name: Some(name.to_string()),
+        module: PhantomData,
+    })
+}
 
-impl<T, C> DynamicModule<C> for ModuleDyn<T>
+pub struct ModuleDyn<T, M = Sequential> {
+    name: Option<String>,
+    module: PhantomData<(T, M)>,
+}
+
+impl<T, C, M> DynamicModule<C> for ModuleDyn<T, M>
 where
     T: Module<C>,
     C: Context,
+    M: BuildMode<T, C>,
 {
     fn config_section(&self) -> &str {
-        T::CONFIG_SECTION
+        self.name.as_deref().unwrap_or(T::CONFIG_SECTION)
+    }
+
+    fn instance(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn type_name(&self) -> &'static str {
+        core::any::type_name::<T>()
     }
 
     fn default_config(&self) -> Option<Value> {
         T::default_config().and_then(|m| vaerdi::ser::to_value(m).ok())
     }
 
+    fn validate_config(&self, config: &Value) -> Result<(), ConfigError> {
+        ConfigError::deserialize::<T::Config>(self.config_section(), config).map(|_| ())
+    }
+
+    fn is_enabled(&self, config: &Config, mode: &Mode) -> bool {
+        T::is_enabled(config, mode)
+    }
+
     fn setup(&self, core: SetupCtx<'_, C>) -> Result<(), Error> {
         T::setup(core)
     }
 
+    fn concurrent(&self) -> bool {
+        M::CONCURRENT
+    }
+
     fn build<'a>(
         &'a self,
         ctx: BuildCtx<'a, C>,
         value: Value,
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
         Box::pin(async move {
-            let Ok(cfg) = vaerdi::de::from_value::<T::Config>(value.clone()) else {
-                panic!("invalid config: {:?}", value);
-            };
-
+            let cfg = ConfigError::deserialize::<T::Config>(self.config_section(), &value)?;
             T::build(ctx, cfg).await?;
             Ok(())
         })
     }
 
+    fn build_concurrent<'a>(
+        &'a self,
+        ctx: SharedBuildCtx<'a, C>,
+        value: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        M::build_concurrent(self.config_section(), ctx, value)
+    }
+
     fn init<'a>(
         &'a self,
         ctx: InitCtx<'a, C>,
@@ -108,4 +269,22 @@ where
     ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
         Box::pin(async move { T::finish(core).await })
     }
+
+    fn shutdown<'a>(
+        &'a self,
+        ctx: ShutdownCtx<'a>,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Error>> + 'a>> {
+        Box::pin(async move { T::shutdown(ctx).await })
+    }
+
+    fn reconfigure<'a>(
+        &'a self,
+        ctx: ReconfigureCtx<'a>,
+        value: Value,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, Error>> + 'a>> {
+        Box::pin(async move {
+            let cfg = ConfigError::deserialize::<T::Config>(self.config_section(), &value)?;
+            T::reconfigure(ctx, cfg).await
+        })
+    }
 }
@@ -0,0 +1,82 @@
+use std::any::{Any, TypeId};
+
+use extensions::concurrent::Extensions;
+use vaerdi::hashbrown::HashMap;
+
+/// Extensions registered by named module instances, keyed by instance name and type.
+#[derive(Default)]
+pub(crate) struct NamedExtensions {
+    map: HashMap<(String, TypeId), Box<dyn Any + Send + Sync>>,
+}
+
+impl NamedExtensions {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, name: &str, value: T) {
+        self.map
+            .insert((name.to_string(), TypeId::of::<T>()), Box::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.map
+            .get(&(name.to_string(), TypeId::of::<T>()))
+            .and_then(|value| (**value).downcast_ref())
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self, name: &str) -> Option<&mut T> {
+        self.map
+            .get_mut(&(name.to_string(), TypeId::of::<T>()))
+            .and_then(|value| (**value).downcast_mut())
+    }
+
+    pub fn extend(&mut self, other: NamedExtensions) {
+        self.map.extend(other.map);
+    }
+}
+
+/// Values registered with `register_lifecycle`, keyed by module instance and type,
+/// so named instances of one module keep separate values.
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    shared: Extensions,
+    named: NamedExtensions,
+}
+
+impl Lifecycle {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, instance: Option<&str>, value: T) {
+        match instance {
+            Some(name) => self.named.insert(name, value),
+            None => {
+                self.shared.insert(value);
+            }
+        }
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self, instance: Option<&str>) -> Option<&T> {
+        match instance {
+            Some(name) => self.named.get::<T>(name),
+            None => self.shared.get::<T>(),
+        }
+    }
+
+    pub fn extend(&mut self, other: Lifecycle) {
+        self.shared.extend(other.shared);
+        self.named.extend(other.named);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle_values_are_kept_per_instance() {
+        let mut lifecycle = Lifecycle::default();
+        lifecycle.insert(None, 1u32);
+        lifecycle.insert(Some("primary"), 2u32);
+        lifecycle.insert(Some("replica"), 3u32);
+
+        assert_eq!(lifecycle.get::<u32>(None), Some(&1));
+        assert_eq!(lifecycle.get::<u32>(Some("primary")), Some(&2));
+        assert_eq!(lifecycle.get::<u32>(Some("replica")), Some(&3));
+        assert_eq!(lifecycle.get::<u32>(Some("other")), None);
+    }
+}
@@ -0,0 +1,63 @@
+use std::any::TypeId;
+
+use extensions::concurrent::Extensions;
+use tracing::info;
+use vaerdi::hashbrown::HashSet;
+
+use crate::providers::Providers;
+
+/// Extension types pinned with `Builder::override_ext`.
+/// Modules and plugins registering one of these types are ignored.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Overrides {
+    types: HashSet<TypeId>,
+}
+
+impl Overrides {
+    /// Pin `value` as the registered value of `T`
+    pub fn pin<T: Send + Sync + 'static>(
+        &mut self,
+        value: T,
+        extensions: &mut Extensions,
+        providers: &mut Providers,
+    ) {
+        info!(ty = core::any::type_name::<T>(), "Overriding extension");
+        providers.insert::<T>("override");
+        self.types.insert(TypeId::of::<T>());
+        extensions.insert(value);
+    }
+
+    pub fn contains_id(&self, id: TypeId) -> bool {
+        self.types.contains(&id)
+    }
+
+    /// Returns `true` if `T` is overridden, logging that the registration from `provider` is ignored
+    pub fn ignores<T: 'static>(&self, provider: &str) -> bool {
+        if !self.contains_id(TypeId::of::<T>()) {
+            return false;
+        }
+
+        info!(
+            provider = provider,
+            ty = core::any::type_name::<T>(),
+            "Extension is overridden. Ignoring registration"
+        );
+
+        true
+    }
+
+    /// Returns `true` if `T` is overridden, logging that mutable access from `provider` is refused
+    pub fn locks<T: 'static>(&self, provider: &str) -> bool {
+        if !self.contains_id(TypeId::of::<T>()) {
+            return false;
+        }
+
+        info!(
+            provider = provider,
+            ty = core::any::type_name::<T>(),
+            "Extension is overridden. Refusing mutable access"
+        );
+
+        true
+    }
+}
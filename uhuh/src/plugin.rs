@@ -1,28 +1,192 @@
 use std::{
     any::{Any, TypeId},
-    collections::HashMap,
+    marker::PhantomData,
     path::Path,
 };
 
 use extensions::concurrent::Extensions;
 use futures_core::{future::BoxFuture, Future};
+use johnfig::Config;
+use tracing::{debug, info};
+use vaerdi::Value;
 
-use crate::{Error, Mode};
+use crate::{
+    builder::{get_section, sort_dependencies},
+    overrides::Overrides,
+    providers::Providers,
+    ConfigError, Error, Mode,
+};
 
 pub trait Plugin<C> {
     type Output;
     type Error: std::error::Error + Send + Sync;
+
+    /// Declare the plugins this plugin reads the output of in [`build_with`](Self::build_with).
+    /// They are built first.
+    fn dependencies(deps: &mut PluginDeps<C>) {
+        let _ = deps;
+    }
+
     fn build(
         self,
         root: &Path,
         mode: &Mode,
     ) -> impl Future<Output = Result<Self::Output, Self::Error>> + Send;
+
+    /// Like [`build`](Self::build), with read access to the extensions, including the outputs
+    /// of the plugins declared in [`dependencies`](Self::dependencies). Defaults to `build`.
+    fn build_with(
+        self,
+        ctx: PluginCtx<'_>,
+    ) -> impl Future<Output = Result<Self::Output, Self::Error>> + Send
+    where
+        Self: Sized,
+    {
+        self.build(ctx.root, ctx.mode)
+    }
+}
+
+/// A plugin that reads its own config section, like a [`Module`](crate::Module).
+/// Register it with [`SetupCtx::register_configured_plugin`](crate::SetupCtx::register_configured_plugin).
+///
+/// The section is deserialized and passed to [`configure`](Self::configure) before the
+/// plugin is built. A missing section fails the build unless `skip_missing_config` is set,
+/// in which case the plugin is not built.
+pub trait ConfiguredPlugin<C>: Plugin<C> {
+    const CONFIG_SECTION: &'static str;
+
+    type Config: serde::Serialize + serde::de::DeserializeOwned + Send;
+
+    /// Merged into the config below any config files,
+    /// like [`Module::default_config`](crate::Module::default_config).
+    fn default_config() -> Option<Self::Config> {
+        None
+    }
+
+    /// Applies the plugin's config section
+    fn configure(&mut self, config: Self::Config) -> Result<(), Self::Error>;
+}
+
+/// How a boxed plugin is configured, either not at all or with [`ConfiguredPlugin`]
+pub(crate) trait ConfigMode<T, C> {
+    fn config_section() -> Option<&'static str>;
+
+    fn default_config() -> Option<Value>;
+
+    fn validate_config(config: &Value) -> Result<(), ConfigError>;
+
+    fn configure(plugin: &mut T, config: &Value) -> Result<(), Error>;
+}
+
+pub(crate) struct Unconfigured;
+
+impl<T: Plugin<C>, C> ConfigMode<T, C> for Unconfigured {
+    fn config_section() -> Option<&'static str> {
+        None
+    }
+
+    fn default_config() -> Option<Value> {
+        None
+    }
+
+    fn validate_config(_: &Value) -> Result<(), ConfigError> {
+        Ok(())
+    }
+
+    fn configure(_: &mut T, _: &Value) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub(crate) struct Configured;
+
+impl<T, C> ConfigMode<T, C> for Configured
+where
+    T: ConfiguredPlugin<C>,
+    T::Error: 'static,
+{
+    fn config_section() -> Option<&'static str> {
+        Some(T::CONFIG_SECTION)
+    }
+
+    fn default_config() -> Option<Value> {
+        T::default_config().and_then(|m| vaerdi::ser::to_value(m).ok())
+    }
+
+    fn validate_config(config: &Value) -> Result<(), ConfigError> {
+        ConfigError::deserialize::<T::Config>(T::CONFIG_SECTION, config).map(|_| ())
+    }
+
+    fn configure(plugin: &mut T, config: &Value) -> Result<(), Error> {
+        let config = ConfigError::deserialize::<T::Config>(T::CONFIG_SECTION, config)?;
+        plugin.configure(config).map_err(Error::new)
+    }
+}
+
+/// The plugins a plugin depends on, see [`Plugin::dependencies`]
+pub struct PluginDeps<C> {
+    deps: Vec<(TypeId, &'static str)>,
+    _ctx: PhantomData<fn() -> C>,
+}
+
+impl<C> Default for PluginDeps<C> {
+    fn default() -> Self {
+        PluginDeps {
+            deps: Vec::default(),
+            _ctx: PhantomData,
+        }
+    }
+}
+
+impl<C> PluginDeps<C> {
+    pub fn add<T: Plugin<C> + 'static>(&mut self) -> &mut Self {
+        self.deps
+            .push((TypeId::of::<T>(), core::any::type_name::<T>()));
+        self
+    }
+}
+
+pub struct PluginCtx<'a> {
+    extensions: &'a Extensions,
+    providers: &'a Providers,
+    root: &'a Path,
+    mode: &'a Mode,
+}
+
+impl<'a> PluginCtx<'a> {
+    pub fn root(&self) -> &'a Path {
+        self.root
+    }
+
+    pub fn mode(&self) -> &'a Mode {
+        self.mode
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&'a T> {
+        self.extensions.get::<T>()
+    }
+
+    /// Like [`get`](Self::get), but fails with an error naming the type
+    /// and the module or plugin expected to register it.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&'a T, Error> {
+        Ok(self.providers.require::<T>(self.extensions)?)
+    }
 }
 
 pub(crate) trait DynamicPlugin<C> {
+    fn config_section(&self) -> Option<&'static str>;
+
+    fn output_id(&self) -> TypeId;
+
+    fn default_config(&self) -> Option<Value>;
+
+    fn validate_config(&self, config: &Value) -> Result<(), ConfigError>;
+
     fn build<'a>(
         self: Box<Self>,
         extensions: &'a mut Extensions,
+        providers: &'a Providers,
+        config: Value,
         root: &'a Path,
         mode: &'a Mode,
     ) -> BoxFuture<'a, Result<(), Error>>;
@@ -31,24 +195,53 @@ pub(crate) trait DynamicPlugin<C> {
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
-pub(crate) struct PluginBox<T> {
+pub(crate) struct PluginBox<T, M = Unconfigured> {
     inner: T,
+    mode: PhantomData<fn() -> M>,
 }
 
-impl<T, C> DynamicPlugin<C> for PluginBox<T>
+impl<T, C, M> DynamicPlugin<C> for PluginBox<T, M>
 where
     T: 'static + Plugin<C> + Send,
     T::Output: Send + Sync + 'static,
     T::Error: 'static,
+    M: ConfigMode<T, C> + 'static,
 {
+    fn config_section(&self) -> Option<&'static str> {
+        M::config_section()
+    }
+
+    fn output_id(&self) -> TypeId {
+        TypeId::of::<T::Output>()
+    }
+
+    fn default_config(&self) -> Option<Value> {
+        M::default_config()
+    }
+
+    fn validate_config(&self, config: &Value) -> Result<(), ConfigError> {
+        M::validate_config(config)
+    }
+
     fn build<'a>(
         self: Box<Self>,
         extensions: &'a mut Extensions,
-        path: &'a Path,
+        providers: &'a Providers,
+        config: Value,
+        root: &'a Path,
         mode: &'a Mode,
     ) -> BoxFuture<'a, Result<(), Error>> {
         Box::pin(async move {
-            let ret = self.inner.build(path, mode).await.map_err(Error::new)?;
+            let mut inner = self.inner;
+            M::configure(&mut inner, &config)?;
+
+            let ctx = PluginCtx {
+                extensions: &*extensions,
+                providers,
+                root,
+                mode,
+            };
+            let ret = inner.build_with(ctx).await.map_err(Error::new)?;
             extensions.insert(ret);
             Ok(())
         })
@@ -71,11 +264,36 @@ where
     T::Output: Send + Sync + 'static,
     T::Error: 'static,
 {
-    Box::new(PluginBox { inner: extension })
+    Box::new(PluginBox::<T, Unconfigured> {
+        inner: extension,
+        mode: PhantomData,
+    })
+}
+
+pub fn configured_plugin_box<T, C>(extension: T) -> BoxPlugin<C>
+where
+    T: 'static + ConfiguredPlugin<C> + Send + Sync,
+    T::Output: Send + Sync + 'static,
+    T::Error: 'static,
+{
+    Box::new(PluginBox::<T, Configured> {
+        inner: extension,
+        mode: PhantomData,
+    })
+}
+
+struct Entry<C> {
+    id: TypeId,
+    name: &'static str,
+    deps: PluginDeps<C>,
+    plugin: BoxPlugin<C>,
+    /// The output is overridden, so the plugin is not built
+    overridden: bool,
 }
 
+/// Plugins are built in insertion order, with dependencies moved before their dependents.
 pub struct PluginsList<C> {
-    plugins: HashMap<TypeId, BoxPlugin<C>>,
+    plugins: Vec<Entry<C>>,
 }
 
 impl<C> Default for PluginsList<C> {
@@ -86,21 +304,48 @@ impl<C> Default for PluginsList<C> {
     }
 }
 impl<C> PluginsList<C> {
+    fn position(&self, id: TypeId) -> Option<usize> {
+        self.plugins.iter().position(|entry| entry.id == id)
+    }
+
     pub fn insert<T>(&mut self, plugin: T) -> Result<(), Error>
     where
         T: 'static + Plugin<C> + Send + Sync,
         T::Output: Send + Sync + 'static,
         T::Error: 'static,
     {
+        self.push::<T>(plugin_box(plugin))
+    }
+
+    /// Adds a plugin that reads its own config section
+    pub fn insert_configured<T>(&mut self, plugin: T) -> Result<(), Error>
+    where
+        T: 'static + ConfiguredPlugin<C> + Send + Sync,
+        T::Output: Send + Sync + 'static,
+        T::Error: 'static,
+    {
+        self.push::<T>(configured_plugin_box(plugin))
+    }
+
+    fn push<T: 'static + Plugin<C>>(&mut self, plugin: BoxPlugin<C>) -> Result<(), Error> {
         let id = TypeId::of::<T>();
-        if self.plugins.contains_key(&id) {
+        if self.position(id).is_some() {
             return Err(Error::new(format!(
                 "Plugin '{}' already defined",
                 core::any::type_name::<T>()
             )));
         }
 
-        self.plugins.insert(id, plugin_box(plugin));
+        let mut deps = PluginDeps::default();
+        T::dependencies(&mut deps);
+
+        self.plugins.push(Entry {
+            id,
+            name: core::any::type_name::<T>(),
+            deps,
+            plugin,
+            overridden: false,
+        });
 
         Ok(())
     }
@@ -111,12 +356,12 @@ impl<C> PluginsList<C> {
         T::Output: Send + Sync + 'static,
         T::Error: 'static,
     {
-        let id = TypeId::of::<T>();
-        let Some(plugin) = self.plugins.get(&id) else {
+        let Some(idx) = self.position(TypeId::of::<T>()) else {
             return Err(Error::new("Plugin not registered"));
         };
 
-        plugin
+        self.plugins[idx]
+            .plugin
             .as_any()
             .downcast_ref()
             .ok_or_else(|| Error::new("Plugin not registered"))
@@ -128,26 +373,284 @@ impl<C> PluginsList<C> {
         T::Output: Send + Sync + 'static,
         T::Error: 'static,
     {
-        let id = TypeId::of::<T>();
-        let Some(plugin) = self.plugins.get_mut(&id) else {
+        let Some(idx) = self.position(TypeId::of::<T>()) else {
             return Err(Error::new("Plugin not registered"));
         };
 
-        plugin
+        self.plugins[idx]
+            .plugin
             .as_any_mut()
             .downcast_mut()
             .ok_or_else(|| Error::new("Plugin not registered"))
     }
 
-    pub async fn build<'a>(
+    /// The order plugins are built in. Fails on unknown dependencies and cycles.
+    fn build_order(&self) -> Result<Vec<usize>, Error> {
+        let mut deps = Vec::with_capacity(self.plugins.len());
+
+        for entry in &self.plugins {
+            let mut entry_deps = Vec::with_capacity(entry.deps.deps.len());
+            for (id, name) in &entry.deps.deps {
+                let Some(dep) = self.position(*id) else {
+                    return Err(Error::new(format!(
+                        "Plugin '{}' depends on '{}', which is not registered",
+                        entry.name, name
+                    )));
+                };
+                entry_deps.push(dep);
+            }
+            deps.push(entry_deps);
+        }
+
+        let (order, _) = sort_dependencies(&deps, 0..self.plugins.len()).map_err(|cycle| {
+            let cycle = cycle
+                .iter()
+                .map(|idx| self.plugins[*idx].name)
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            Error::new(format!("Plugin dependency cycle: {cycle}"))
+        })?;
+
+        Ok(order)
+    }
+
+    /// Skip plugins whose output is pinned with `Builder::override_ext`
+    pub(crate) fn skip_overridden(&mut self, overrides: &Overrides) {
+        for entry in &mut self.plugins {
+            if overrides.contains_id(entry.plugin.output_id()) {
+                info!(plugin = ?entry.name, "Plugin output is overridden. Skipping");
+                entry.overridden = true;
+            }
+        }
+    }
+
+    /// Default configs of the plugins with a config section, keyed by section
+    pub(crate) fn default_configs(&self) -> impl Iterator<Item = (&'static str, Value)> + '_ {
+        self.plugins.iter().filter_map(|entry| {
+            let section = entry.plugin.config_section()?;
+            entry.plugin.default_config().map(|cfg| (section, cfg))
+        })
+    }
+
+    /// Deserializes each plugin's config section and builds the plugins in dependency order.
+    pub(crate) async fn build<'a>(
         self,
         extensions: &'a mut Extensions,
+        providers: &'a Providers,
+        config: &'a Config,
+        skip_missing_config: bool,
         root: &'a Path,
         mode: &'a Mode,
     ) -> Result<(), Error> {
-        for plugin in self.plugins.into_values() {
-            plugin.build(extensions, root, mode).await?;
+        let order = self.build_order()?;
+
+        let mut errors = Vec::default();
+        let mut plugins = Vec::with_capacity(self.plugins.len());
+
+        for entry in self.plugins {
+            if entry.overridden {
+                plugins.push(None);
+                continue;
+            }
+
+            let cfg = match entry.plugin.config_section() {
+                Some(section) => match get_section(config, section) {
+                    Some(cfg) => cfg,
+                    None => {
+                        if skip_missing_config {
+                            debug!(plugin = ?entry.name, "Missing config. Skipping");
+                        } else {
+                            errors.push(ConfigError::missing(section));
+                        }
+                        plugins.push(None);
+                        continue;
+                    }
+                },
+                None => entry.plugin.default_config().unwrap_or(Value::Null),
+            };
+
+            if let Err(err) = entry.plugin.validate_config(&cfg) {
+                errors.push(err);
+            }
+
+            plugins.push(Some((entry.plugin, cfg)));
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::Config(errors));
+        }
+
+        for idx in order {
+            if let Some((plugin, cfg)) = plugins[idx].take() {
+                plugin.build(extensions, providers, cfg, root, mode).await?;
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! plugin {
+        ($name:ident $(, $dep:ident)*) => {
+            struct $name;
+
+            impl Plugin<()> for $name {
+                type Output = ();
+                type Error = std::fmt::Error;
+
+                fn dependencies(deps: &mut PluginDeps<()>) {
+                    let _ = &deps;
+                    $(deps.add::<$dep>();)*
+                }
+
+                async fn build(self, _: &Path, _: &Mode) -> Result<(), Self::Error> {
+                    Ok(())
+                }
+            }
+        };
+    }
+
+    plugin!(A);
+    plugin!(B, A);
+    plugin!(C, B);
+    plugin!(Cycle1, Cycle2);
+    plugin!(Cycle2, Cycle1);
+    plugin!(Unknown, A);
+
+    fn order(list: &PluginsList<()>) -> Vec<&'static str> {
+        list.build_order()
+            .unwrap()
+            .into_iter()
+            .map(|idx| list.plugins[idx].name.rsplit("::").next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn build_order_keeps_insertion_order() {
+        let mut list = PluginsList::default();
+        list.insert(A).unwrap();
+        list.insert(B).unwrap();
+        list.insert(C).unwrap();
+
+        assert_eq!(order(&list), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn build_order_moves_dependencies_first() {
+        let mut list = PluginsList::default();
+        list.insert(C).unwrap();
+        list.insert(B).unwrap();
+        list.insert(A).unwrap();
+
+        assert_eq!(order(&list), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn build_order_fails_on_cycles() {
+        let mut list = PluginsList::default();
+        list.insert(Cycle1).unwrap();
+        list.insert(Cycle2).unwrap();
+
+        let err = list.build_order().unwrap_err().to_string();
+        assert!(err.starts_with("Plugin dependency cycle: "), "{err}");
+        assert!(
+            err.contains("Cycle1 -> ") && err.contains("Cycle2"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn build_order_fails_on_unknown_dependencies() {
+        let mut list = PluginsList::default();
+        list.insert(Unknown).unwrap();
+
+        let err = list.build_order().unwrap_err().to_string();
+        assert!(err.contains("which is not registered"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn plugins_without_a_section_are_always_built() {
+        let mut list = PluginsList::<()>::default();
+        list.insert(A).unwrap();
+
+        let mut extensions = Extensions::default();
+        list.build(
+            &mut extensions,
+            &Providers::default(),
+            &Config::default(),
+            true,
+            Path::new("."),
+            &Mode::Development,
+        )
+        .await
+        .unwrap();
+
+        assert!(extensions.get::<()>().is_some());
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct PortConfig {
+        port: u16,
+    }
+
+    struct Port {
+        port: u16,
+    }
+
+    impl Plugin<()> for Port {
+        type Output = u16;
+        type Error = std::fmt::Error;
+
+        async fn build(self, _: &Path, _: &Mode) -> Result<u16, Self::Error> {
+            Ok(self.port)
+        }
+    }
+
+    impl ConfiguredPlugin<()> for Port {
+        const CONFIG_SECTION: &'static str = "port";
+        type Config = PortConfig;
+
+        fn configure(&mut self, config: PortConfig) -> Result<(), Self::Error> {
+            self.port = config.port;
+            Ok(())
+        }
+    }
+
+    async fn build_port(config: &Config) -> Result<Extensions, Error> {
+        let mut list = PluginsList::<()>::default();
+        list.insert_configured(Port { port: 0 }).unwrap();
+
+        let mut extensions = Extensions::default();
+        list.build(
+            &mut extensions,
+            &Providers::default(),
+            config,
+            false,
+            Path::new("."),
+            &Mode::Development,
+        )
+        .await?;
+
+        Ok(extensions)
+    }
+
+    #[tokio::test]
+    async fn configured_plugins_read_their_section() {
+        let mut config = Config::default();
+        crate::builder::set_section(
+            &mut config,
+            "port",
+            vaerdi::ser::to_value(PortConfig { port: 8080 }).unwrap(),
+        )
+        .unwrap();
+
+        let extensions = build_port(&config).await.unwrap();
+        assert_eq!(extensions.get::<u16>(), Some(&8080));
+
+        let err = build_port(&Config::default()).await.unwrap_err();
+        assert_eq!(err.config_errors()[0].section, "port");
+    }
+}
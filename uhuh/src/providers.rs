@@ -0,0 +1,34 @@
+use std::any::TypeId;
+
+use extensions::concurrent::Extensions;
+pub use uhuh_ext::MissingExtension;
+use vaerdi::hashbrown::HashMap;
+
+/// Records which module or plugin registered each extension type,
+/// so a missing value can be traced back to where it should come from.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Providers {
+    map: HashMap<TypeId, String>,
+}
+
+impl Providers {
+    pub fn insert<T: 'static>(&mut self, provider: &str) {
+        self.map.insert(TypeId::of::<T>(), provider.to_string());
+    }
+
+    pub fn extend(&mut self, other: Providers) {
+        self.map.extend(other.map);
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&str> {
+        self.map.get(&TypeId::of::<T>()).map(|m| m.as_str())
+    }
+
+    pub fn require<'a, T: Send + Sync + 'static>(
+        &self,
+        ext: &'a Extensions,
+    ) -> Result<&'a T, MissingExtension> {
+        ext.get::<T>()
+            .ok_or_else(|| MissingExtension::new::<T>().with_provider(self.get::<T>()))
+    }
+}
@@ -0,0 +1,342 @@
+use core::fmt;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{info_span, Instrument, Span};
+
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModulePhase {
+    Setup,
+    Build,
+    Init,
+    Finish,
+}
+
+impl ModulePhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModulePhase::Setup => "setup",
+            ModulePhase::Build => "build",
+            ModulePhase::Init => "init",
+            ModulePhase::Finish => "finish",
+        }
+    }
+}
+
+impl fmt::Display for ModulePhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    /// The module was not built because its config section is missing
+    Skipped,
+    /// The module was disabled by its `enabled` config key or `Module::is_enabled`,
+    /// or because one of its dependencies was disabled
+    Disabled,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    pub module: String,
+    pub phase: ModulePhase,
+    pub duration: Duration,
+    pub outcome: Outcome,
+}
+
+/// Timings for each module phase during startup.
+///
+/// Enable with `Builder::startup_report`, then read it from the `Uhuh` extensions.
+/// The setup time of a module includes the setup of dependencies added with `depends_on`.
+#[derive(Debug, Clone, Default)]
+pub struct StartupReport {
+    entries: Arc<Mutex<Vec<ReportEntry>>>,
+}
+
+impl StartupReport {
+    /// All entries in the order the phases completed
+    pub fn entries(&self) -> Vec<ReportEntry> {
+        self.entries
+            .lock()
+            .map(|entries| entries.clone())
+            .unwrap_or_default()
+    }
+
+    /// Time spent in all phases of `module`
+    pub fn total(&self, module: &str) -> Duration {
+        self.entries()
+            .iter()
+            .filter(|entry| entry.module == module)
+            .map(|entry| entry.duration)
+            .sum()
+    }
+
+    /// Modules ordered by the total time spent in them, slowest first
+    pub fn slowest(&self) -> Vec<(String, Duration)> {
+        let mut modules: Vec<(String, Duration)> = Vec::default();
+
+        for entry in self.entries() {
+            match modules
+                .iter_mut()
+                .find(|(module, _)| *module == entry.module)
+            {
+                Some((_, total)) => *total += entry.duration,
+                None => modules.push((entry.module, entry.duration)),
+            }
+        }
+
+        modules.sort_by(|a, b| b.1.cmp(&a.1));
+        modules
+    }
+
+    pub(crate) fn record(
+        &self,
+        module: &str,
+        phase: ModulePhase,
+        duration: Duration,
+        outcome: Outcome,
+    ) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.push(ReportEntry {
+                module: module.to_string(),
+                phase,
+                duration,
+                outcome,
+            });
+        }
+    }
+}
+
+impl fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.entries() {
+            write!(
+                f,
+                "{:<24} {:<8} {:>12?}",
+                entry.module, entry.phase, entry.duration
+            )?;
+            match entry.outcome {
+                Outcome::Completed => writeln!(f)?,
+                Outcome::Skipped => writeln!(f, "  skipped")?,
+                Outcome::Disabled => writeln!(f, "  disabled")?,
+                Outcome::Failed(err) => writeln!(f, "  failed: {err}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of a registered module, from `Builder::modules` or `Uhuh::modules`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub type_name: &'static str,
+    pub section: String,
+    /// The module whose setup added this module, or `None` if it was added on the builder
+    pub added_by: Option<String>,
+    /// The module was not built because its config section is missing and `skip_missing_config` is set
+    pub skipped: bool,
+    /// The module was disabled by its `enabled` config key or `Module::is_enabled`,
+    /// or because one of its dependencies was disabled
+    pub disabled: bool,
+    /// The disabled dependency that caused this module to be disabled
+    pub disabled_by: Option<String>,
+    /// Phases that completed successfully, in order
+    pub phases: Vec<ModulePhase>,
+}
+
+/// Shared record of module states, kept up to date as the builder moves through the phases.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ModuleStates {
+    modules: Arc<Mutex<Vec<ModuleInfo>>>,
+}
+
+impl ModuleStates {
+    pub fn new(modules: Vec<ModuleInfo>) -> ModuleStates {
+        ModuleStates {
+            modules: Arc::new(Mutex::new(modules)),
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ModuleInfo> {
+        self.modules
+            .lock()
+            .map(|modules| modules.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn complete(&self, section: &str, phase: ModulePhase) {
+        self.update(section, |info| info.phases.push(phase));
+    }
+
+    pub fn skip(&self, section: &str) {
+        self.update(section, |info| info.skipped = true);
+    }
+
+    pub fn disable(&self, section: &str, dependency: Option<&str>) {
+        self.update(section, |info| {
+            info.disabled = true;
+            info.disabled_by = dependency.map(|m| m.to_string());
+        });
+    }
+
+    fn update(&self, section: &str, func: impl FnOnce(&mut ModuleInfo)) {
+        if let Ok(mut modules) = self.modules.lock() {
+            if let Some(info) = modules.iter_mut().find(|info| info.section == section) {
+                func(info);
+            }
+        }
+    }
+}
+
+fn span(module: &str, phase: ModulePhase) -> Span {
+    info_span!("module", module = module, phase = phase.as_str())
+}
+
+fn outcome<T>(ret: &Result<T, Error>) -> Outcome {
+    match ret {
+        Ok(_) => Outcome::Completed,
+        Err(err) => Outcome::Failed(err.to_string()),
+    }
+}
+
+/// Runs a module phase inside a span, recording it in `report` if enabled.
+pub(crate) fn phase<T>(
+    report: Option<&StartupReport>,
+    module: &str,
+    phase: ModulePhase,
+    func: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let ret = span(module, phase).in_scope(func);
+
+    if let Some(report) = report {
+        report.record(module, phase, start.elapsed(), outcome(&ret));
+    }
+
+    ret
+}
+
+/// Async version of [`phase`]
+pub(crate) async fn phase_async<T>(
+    report: Option<&StartupReport>,
+    module: &str,
+    phase: ModulePhase,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let ret = future.instrument(span(module, phase)).await;
+
+    if let Some(report) = report {
+        report.record(module, phase, start.elapsed(), outcome(&ret));
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(section: &str) -> ModuleInfo {
+        ModuleInfo {
+            type_name: "test",
+            section: section.to_string(),
+            added_by: None,
+            skipped: false,
+            disabled: false,
+            disabled_by: None,
+            phases: vec![ModulePhase::Setup],
+        }
+    }
+
+    fn outcomes(report: &StartupReport) -> Vec<(String, ModulePhase, Outcome)> {
+        report
+            .entries()
+            .into_iter()
+            .map(|entry| (entry.module, entry.phase, entry.outcome))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_each_phase_with_its_outcome() {
+        let report = StartupReport::default();
+
+        phase(Some(&report), "db", ModulePhase::Setup, || Ok(())).unwrap();
+        phase_async(Some(&report), "db", ModulePhase::Build, async { Ok(()) })
+            .await
+            .unwrap();
+        report.record(
+            "cache",
+            ModulePhase::Build,
+            Duration::ZERO,
+            Outcome::Disabled,
+        );
+        phase_async(Some(&report), "http", ModulePhase::Init, async {
+            Err::<(), _>(Error::new("port in use"))
+        })
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            outcomes(&report),
+            vec![
+                ("db".to_string(), ModulePhase::Setup, Outcome::Completed),
+                ("db".to_string(), ModulePhase::Build, Outcome::Completed),
+                ("cache".to_string(), ModulePhase::Build, Outcome::Disabled),
+                (
+                    "http".to_string(),
+                    ModulePhase::Init,
+                    Outcome::Failed("port in use".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn sums_durations_per_module() {
+        let report = StartupReport::default();
+        let ms = Duration::from_millis;
+
+        report.record("db", ModulePhase::Setup, ms(1), Outcome::Completed);
+        report.record("http", ModulePhase::Setup, ms(2), Outcome::Completed);
+        report.record("db", ModulePhase::Build, ms(4), Outcome::Completed);
+
+        assert_eq!(report.total("db"), ms(5));
+        assert_eq!(report.total("missing"), Duration::ZERO);
+        assert_eq!(
+            report.slowest(),
+            vec![("db".to_string(), ms(5)), ("http".to_string(), ms(2))]
+        );
+    }
+
+    #[test]
+    fn tracks_module_states() {
+        let states = ModuleStates::new(vec![info("db"), info("cache"), info("http")]);
+
+        states.complete("db", ModulePhase::Build);
+        states.complete("db", ModulePhase::Init);
+        states.skip("cache");
+        states.disable("http", Some("db"));
+        states.complete("unknown", ModulePhase::Build);
+
+        let modules = states.snapshot();
+        assert_eq!(
+            modules[0].phases,
+            vec![ModulePhase::Setup, ModulePhase::Build, ModulePhase::Init]
+        );
+        assert!(modules[1].skipped);
+        assert_eq!(modules[1].phases, vec![ModulePhase::Setup]);
+        assert!(modules[2].disabled);
+        assert_eq!(modules[2].disabled_by.as_deref(), Some("db"));
+        assert_eq!(modules.len(), 3);
+    }
+}
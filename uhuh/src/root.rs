@@ -0,0 +1,217 @@
+use core::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+/// Where to start walking up the directory tree looking for a root marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootSearch {
+    CurrentDir,
+    /// The directory of the running executable
+    Executable,
+}
+
+/// How the root directory was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootSource {
+    Explicit,
+    Env(String),
+    Marker(String),
+    CurrentDir,
+}
+
+impl fmt::Display for RootSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootSource::Explicit => f.write_str("explicit"),
+            RootSource::Env(var) => write!(f, "env {var}"),
+            RootSource::Marker(marker) => write!(f, "marker {marker}"),
+            RootSource::CurrentDir => f.write_str("current dir"),
+        }
+    }
+}
+
+/// Finds the root directory when it is not set on the builder or with `--root`.
+///
+/// The environment variable is tried first. Then, if a marker search is enabled, each
+/// search start is walked up until a directory containing one of the markers is found.
+/// If nothing matches, the current directory is used. By default, only the current
+/// directory is used.
+#[derive(Debug, Clone, Default)]
+pub struct RootResolver {
+    env: Option<String>,
+    markers: Vec<String>,
+    search: Vec<RootSearch>,
+}
+
+impl RootResolver {
+    /// Only use the current directory
+    pub fn current_dir() -> RootResolver {
+        RootResolver::default()
+    }
+
+    /// Walk up from the current directory, then from the executable, to a directory
+    /// containing `uhuh.toml` or `.git`
+    pub fn find_markers(mut self) -> Self {
+        if self.markers.is_empty() {
+            self.markers = vec!["uhuh.toml".to_string(), ".git".to_string()];
+        }
+        if self.search.is_empty() {
+            self.search = vec![RootSearch::CurrentDir, RootSearch::Executable];
+        }
+        self
+    }
+
+    /// Read the root from the environment variable `name`, if set
+    pub fn env(mut self, name: impl ToString) -> Self {
+        self.env = Some(name.to_string());
+        self
+    }
+
+    /// Replace the marker files, like `uhuh.toml` or `.git`.
+    /// The search starts from the current directory unless set with [`search`](Self::search).
+    pub fn markers<I>(mut self, markers: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: ToString,
+    {
+        self.markers = markers.into_iter().map(|m| m.to_string()).collect();
+        self.search_current_dir()
+    }
+
+    pub fn marker(mut self, marker: impl ToString) -> Self {
+        self.markers.push(marker.to_string());
+        self.search_current_dir()
+    }
+
+    fn search_current_dir(mut self) -> Self {
+        if self.search.is_empty() {
+            self.search.push(RootSearch::CurrentDir);
+        }
+        self
+    }
+
+    /// Replace where the marker search starts, in order
+    pub fn search(mut self, search: impl IntoIterator<Item = RootSearch>) -> Self {
+        self.search = search.into_iter().collect();
+        self
+    }
+
+    pub fn resolve(&self, explicit: Option<PathBuf>) -> Result<(PathBuf, RootSource), Error> {
+        if let Some(path) = explicit {
+            return Ok((path, RootSource::Explicit));
+        }
+
+        if let Some(name) = &self.env {
+            if let Some(path) = std::env::var_os(name) {
+                let path = Path::new(&path).canonicalize().map_err(Error::new)?;
+                return Ok((path, RootSource::Env(name.clone())));
+            }
+        }
+
+        let current = std::env::current_dir().map_err(Error::new)?;
+
+        for search in &self.search {
+            let start = match search {
+                RootSearch::CurrentDir => current.clone(),
+                RootSearch::Executable => match std::env::current_exe() {
+                    Ok(exe) => match exe.parent() {
+                        Some(parent) => parent.to_path_buf(),
+                        None => continue,
+                    },
+                    Err(_) => continue,
+                },
+            };
+
+            if let Some((path, marker)) = self.find_marker(&start) {
+                return Ok((path, RootSource::Marker(marker)));
+            }
+        }
+
+        Ok((current, RootSource::CurrentDir))
+    }
+
+    fn find_marker(&self, start: &Path) -> Option<(PathBuf, String)> {
+        start.ancestors().find_map(|dir| {
+            self.markers
+                .iter()
+                .find(|marker| dir.join(marker).exists())
+                .map(|marker| (dir.to_path_buf(), marker.clone()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("uhuh-root-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn defaults_to_the_current_dir() {
+        let (path, source) = RootResolver::default().resolve(None).unwrap();
+
+        assert_eq!(path, std::env::current_dir().unwrap());
+        assert_eq!(source, RootSource::CurrentDir);
+    }
+
+    #[test]
+    fn explicit_root_wins() {
+        let explicit = PathBuf::from("/srv/app");
+        let (path, source) = RootResolver::default()
+            .env("UHUH_ROOT_TEST_EXPLICIT")
+            .find_markers()
+            .resolve(Some(explicit.clone()))
+            .unwrap();
+
+        assert_eq!(path, explicit);
+        assert_eq!(source, RootSource::Explicit);
+    }
+
+    #[test]
+    fn reads_the_env_var() {
+        let dir = temp_dir("env");
+        std::env::set_var("UHUH_ROOT_TEST_ENV", &dir);
+
+        let (path, source) = RootResolver::default()
+            .env("UHUH_ROOT_TEST_ENV")
+            .resolve(None)
+            .unwrap();
+
+        assert_eq!(path, dir);
+        assert_eq!(source, RootSource::Env("UHUH_ROOT_TEST_ENV".to_string()));
+    }
+
+    #[test]
+    fn finds_markers_in_parent_dirs() {
+        let dir = temp_dir("marker");
+        let nested = dir.join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(dir.join("app.marker"), "").unwrap();
+
+        let resolver = RootResolver::default().marker("app.marker");
+
+        assert_eq!(
+            resolver.find_marker(&nested),
+            Some((dir, "app.marker".to_string()))
+        );
+    }
+
+    #[test]
+    fn marker_search_is_opt_in() {
+        let resolver = RootResolver::default();
+        assert!(resolver.markers.is_empty());
+        assert!(resolver.search.is_empty());
+
+        let resolver = resolver.find_markers();
+        assert_eq!(resolver.markers, vec!["uhuh.toml", ".git"]);
+        assert_eq!(
+            resolver.search,
+            vec![RootSearch::CurrentDir, RootSearch::Executable]
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::{path::PathBuf, sync::Arc};
+
+use johnfig::Config;
+use serde_json::Value;
+
+use crate::{ConfigError, Error};
+
+/// Resolves `${scheme:key}` references in config strings.
+///
+/// Providers are registered on the builder under a scheme. `env` and `file` are built in,
+/// `secret` has no default provider.
+pub trait SecretProvider: Send + Sync {
+    /// Returns `Ok(None)` if the key does not exist.
+    fn resolve(&self, key: &str) -> Result<Option<String>, Error>;
+}
+
+impl<T> SecretProvider for T
+where
+    T: Fn(&str) -> Result<Option<String>, Error> + Send + Sync,
+{
+    fn resolve(&self, key: &str) -> Result<Option<String>, Error> {
+        (self)(key)
+    }
+}
+
+/// Resolves `${env:VAR}` from the process environment.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn resolve(&self, key: &str) -> Result<Option<String>, Error> {
+        match std::env::var(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(err) => Err(Error::new(err)),
+        }
+    }
+}
+
+/// Resolves `${file:path}` to the contents of the file, without the trailing newline.
+///
+/// With [`FileProvider::dir`], keys are file names inside the directory,
+/// which suits mounted secrets: `secret_provider("secret", FileProvider::dir("/run/secrets"))`.
+#[derive(Debug, Clone, Default)]
+pub struct FileProvider {
+    dir: Option<PathBuf>,
+}
+
+impl FileProvider {
+    pub fn new() -> FileProvider {
+        FileProvider::default()
+    }
+
+    pub fn dir(path: impl Into<PathBuf>) -> FileProvider {
+        FileProvider {
+            dir: Some(path.into()),
+        }
+    }
+}
+
+impl SecretProvider for FileProvider {
+    fn resolve(&self, key: &str) -> Result<Option<String>, Error> {
+        let path = match &self.dir {
+            Some(dir) => dir.join(key),
+            None => PathBuf::from(key),
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(content.trim_end_matches(['\r', '\n']).to_string())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::new(err)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SecretProviders {
+    providers: Vec<(String, Arc<dyn SecretProvider>)>,
+}
+
+impl Default for SecretProviders {
+    fn default() -> Self {
+        let mut providers = SecretProviders {
+            providers: Vec::default(),
+        };
+        providers.insert("env", Arc::new(EnvProvider));
+        providers.insert("file", Arc::new(FileProvider::new()));
+        providers
+    }
+}
+
+impl SecretProviders {
+    pub fn insert(&mut self, scheme: &str, provider: Arc<dyn SecretProvider>) {
+        self.providers.retain(|(name, _)| name != scheme);
+        self.providers.push((scheme.to_string(), provider));
+    }
+
+    fn resolve(&self, scheme: &str, key: &str) -> Result<String, String> {
+        let Some((_, provider)) = self.providers.iter().find(|(name, _)| name == scheme) else {
+            return Err(format!("no secret provider registered for '{scheme}'"));
+        };
+
+        match provider.resolve(key) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(format!("'${{{scheme}:{key}}}' not found")),
+            Err(err) => Err(format!("could not resolve '${{{scheme}:{key}}}': {err}")),
+        }
+    }
+
+    /// Replaces all references in string values of `config`, returning the paths
+    /// of the values a reference was resolved in, starting with the section.
+    /// Every unresolved reference is reported with its config path.
+    pub fn interpolate(&self, config: &mut Config) -> Result<Vec<Vec<String>>, Error> {
+        let Value::Object(sections) = serde_json::to_value(&*config).map_err(Error::new)? else {
+            return Ok(Vec::default());
+        };
+
+        let mut resolved = Vec::default();
+        let mut errors = Vec::default();
+
+        for (section, mut value) in sections {
+            let mut path = Vec::default();
+            if self.interpolate_value(&section, &mut path, &mut value, &mut resolved, &mut errors) {
+                config.set(&section, vaerdi::ser::to_value(value)?);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(resolved)
+        } else {
+            Err(Error::Config(errors))
+        }
+    }
+
+    /// Returns `true` if anything was replaced
+    fn interpolate_value(
+        &self,
+        section: &str,
+        path: &mut Vec<String>,
+        value: &mut Value,
+        resolved: &mut Vec<Vec<String>>,
+        errors: &mut Vec<ConfigError>,
+    ) -> bool {
+        match value {
+            Value::String(input) => match self.interpolate_str(input) {
+                Ok(Some((output, has_reference))) => {
+                    if has_reference {
+                        resolved.push(
+                            std::iter::once(section.to_string())
+                                .chain(path.iter().cloned())
+                                .collect(),
+                        );
+                    }
+                    *input = output;
+                    true
+                }
+                Ok(None) => false,
+                Err(message) => {
+                    errors.push(ConfigError {
+                        section: section.to_string(),
+                        path: (!path.is_empty()).then(|| path.join(".")),
+                        expected: None,
+                        message,
+                    });
+                    false
+                }
+            },
+            Value::Array(list) => {
+                let mut changed = false;
+                for (idx, item) in list.iter_mut().enumerate() {
+                    path.push(idx.to_string());
+                    changed |= self.interpolate_value(section, path, item, resolved, errors);
+                    path.pop();
+                }
+                changed
+            }
+            Value::Object(map) => {
+                let mut changed = false;
+                for (key, item) in map.iter_mut() {
+                    path.push(key.clone());
+                    changed |= self.interpolate_value(section, path, item, resolved, errors);
+                    path.pop();
+                }
+                changed
+            }
+            _ => false,
+        }
+    }
+
+    /// `$${` escapes a literal `${`. References without a scheme are left as is.
+    /// Returns the new string, and whether a reference was resolved in it.
+    fn interpolate_str(&self, input: &str) -> Result<Option<(String, bool)>, String> {
+        if !input.contains("${") {
+            return Ok(None);
+        }
+
+        let mut output = String::with_capacity(input.len());
+        let mut rest = input;
+        let mut has_reference = false;
+
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                output.push_str(&rest[..start - 1]);
+                output.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            output.push_str(&rest[..start]);
+
+            let reference = &rest[start + 2..];
+            let Some(end) = reference.find('}') else {
+                output.push_str(&rest[start..]);
+                rest = "";
+                break;
+            };
+
+            match reference[..end].split_once(':') {
+                Some((scheme, key)) => {
+                    output.push_str(&self.resolve(scheme, key)?);
+                    has_reference = true;
+                }
+                None => output.push_str(&rest[start..start + end + 3]),
+            }
+
+            rest = &reference[end + 1..];
+        }
+
+        output.push_str(rest);
+
+        Ok(Some((output, has_reference)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn providers() -> SecretProviders {
+        let mut providers = SecretProviders::default();
+        providers.insert(
+            "test",
+            Arc::new(|key: &str| Ok((key == "token").then(|| "hunter2".to_string()))),
+        );
+        providers
+    }
+
+    #[test]
+    fn interpolate_str_resolves_references() {
+        let providers = providers();
+
+        assert_eq!(providers.interpolate_str("plain"), Ok(None));
+        assert_eq!(
+            providers.interpolate_str("Bearer ${test:token}"),
+            Ok(Some(("Bearer hunter2".to_string(), true)))
+        );
+        assert_eq!(
+            providers.interpolate_str("${test:token}:${test:token}"),
+            Ok(Some(("hunter2:hunter2".to_string(), true)))
+        );
+    }
+
+    #[test]
+    fn interpolate_str_keeps_escapes_and_plain_braces() {
+        let providers = providers();
+
+        assert_eq!(
+            providers.interpolate_str("$${test:token}"),
+            Ok(Some(("${test:token}".to_string(), false)))
+        );
+        assert_eq!(
+            providers.interpolate_str("${name} and ${open"),
+            Ok(Some(("${name} and ${open".to_string(), false)))
+        );
+    }
+
+    #[test]
+    fn interpolate_str_reports_unresolved_references() {
+        let providers = providers();
+
+        assert_eq!(
+            providers.interpolate_str("${test:missing}"),
+            Err("'${test:missing}' not found".to_string())
+        );
+        assert_eq!(
+            providers.interpolate_str("${vault:token}"),
+            Err("no secret provider registered for 'vault'".to_string())
+        );
+    }
+
+    #[test]
+    fn interpolate_returns_resolved_paths() {
+        let mut config = Config::default();
+        config.set(
+            "db",
+            vaerdi::ser::to_value(serde_json::json!({
+                "host": "localhost",
+                "credentials": { "token": "${test:token}" },
+                "hosts": ["a", "$${test:token}"],
+            }))
+            .unwrap(),
+        );
+
+        let resolved = providers().interpolate(&mut config).unwrap();
+
+        assert_eq!(resolved, vec![vec!["db", "credentials", "token"]]);
+    }
+}
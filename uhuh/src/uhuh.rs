@@ -1,16 +1,30 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, RwLockReadGuard},
+};
 
 use extensions::concurrent::Extensions;
 use johnfig::Config;
 
-use crate::Mode;
+use crate::{
+    named::NamedExtensions,
+    overrides::Overrides,
+    providers::Providers,
+    report::{ModuleInfo, ModuleStates},
+    Error, Mode,
+};
 
 pub struct Uhuh {
     pub extensions: Extensions,
-    pub config: Config,
+    pub(crate) config: SharedConfig,
     pub mode: Mode,
     pub name: String,
     pub root: PathBuf,
+    pub(crate) providers: Providers,
+    pub(crate) named: NamedExtensions,
+    pub(crate) overrides: Overrides,
+    pub(crate) states: ModuleStates,
 }
 
 impl Uhuh {
@@ -22,8 +36,11 @@ impl Uhuh {
         &self.mode
     }
 
+    /// Register a value. Values of types pinned with `override_ext` are ignored.
     pub fn register<T: Send + Sync + 'static>(&mut self, value: T) -> &mut Self {
-        self.extensions.insert(value);
+        if !self.overrides.ignores::<T>(&self.name) {
+            self.extensions.insert(value);
+        }
         self
     }
 
@@ -31,11 +48,60 @@ impl Uhuh {
         self.extensions.get::<T>()
     }
 
+    /// Get a value registered by the named module instance `name`
+    pub fn get_named<T: Send + Sync + 'static>(&self, name: &str) -> Option<&T> {
+        self.named.get::<T>(name)
+    }
+
+    pub fn get_named_mut<T: Send + Sync + 'static>(&mut self, name: &str) -> Option<&mut T> {
+        self.named.get_mut::<T>(name)
+    }
+
+    /// Like [`get`](Self::get), but fails with an error naming the type
+    /// and the module expected to register it.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<&T, Error> {
+        Ok(self.providers.require::<T>(&self.extensions)?)
+    }
+
+    /// Get a value mutably. Values pinned with `override_ext` can not be changed, so this returns `None` for them.
     pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        if self.overrides.locks::<T>(&self.name) {
+            return None;
+        }
         self.extensions.get_mut::<T>()
     }
 
-    pub fn config(&self) -> &Config {
-        &self.config
+    /// The current config. When the config is reloaded through a
+    /// [`ConfigWatcher`](crate::builder::ConfigWatcher), this is the reloaded config.
+    pub fn config(&self) -> impl Deref<Target = Config> + '_ {
+        self.config.read()
+    }
+
+    /// A snapshot of the registered modules and the phases they completed
+    pub fn modules(&self) -> Vec<ModuleInfo> {
+        self.states.snapshot()
+    }
+}
+
+/// The config of a running app, shared with the config watcher
+#[derive(Clone)]
+pub(crate) struct SharedConfig(Arc<RwLock<Config>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig(Arc::new(RwLock::new(config)))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Config> {
+        self.0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn replace(&self, config: Config) {
+        *self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = config;
     }
 }